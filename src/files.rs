@@ -0,0 +1,461 @@
+pub(crate) mod listing;
+pub(crate) mod mime;
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PathError {
+    /// The path is not properly percent-encoded, or decodes to something no file can be
+    /// named like.
+    Invalid,
+    /// The path would end up outside of the served directory.
+    Forbidden,
+    NotFound,
+}
+
+impl PathError {
+    pub(crate) fn status_code(&self) -> u16 {
+        match self {
+            PathError::Invalid => 400,
+            PathError::Forbidden => 403,
+            PathError::NotFound => 404,
+        }
+    }
+}
+
+/// Status code to answer with when accessing a file failed with `err`.
+pub(crate) fn io_error_status_code(err: &std::io::Error) -> u16 {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => 404,
+        std::io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    }
+}
+
+/// Decodes `%XX` escapes, see RFC 3986 section 2.1.
+pub(crate) fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut pos = 0;
+    while pos < input.len() {
+        if input[pos] != b'%' {
+            decoded.push(input[pos]);
+            pos += 1;
+            continue;
+        }
+        let hex = input.get(pos + 1..pos + 3)?;
+        let hex = std::str::from_utf8(hex).ok()?;
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        pos += 3;
+    }
+    Some(decoded)
+}
+
+/// The `--directory` the `/files` endpoints serve from, in canonical form.
+pub(crate) struct ServedDirectory {
+    root: PathBuf,
+    /// Whether symlinks pointing outside of `root` may be followed.
+    follow_symlinks: bool,
+}
+
+impl ServedDirectory {
+    pub(crate) fn new(root: impl AsRef<Path>, follow_symlinks: bool) -> anyhow::Result<Self> {
+        let root = root.as_ref();
+        let root = root
+            .canonicalize()
+            .map_err(|e| anyhow::anyhow!("can't serve {}: {e}", root.display()))?;
+        Ok(Self {
+            root,
+            follow_symlinks,
+        })
+    }
+
+    /// Maps the percent-encoded `url_path` onto a path under the served directory. `..` can't
+    /// climb above the served directory, and unless symlinks may be followed, neither can a
+    /// symlink. The target itself doesn't need to exist, so this works for uploads too.
+    pub(crate) fn resolve(&self, url_path: &str) -> Result<PathBuf, PathError> {
+        let decoded = percent_decode(url_path).ok_or(PathError::Invalid)?;
+        if decoded.contains(&0) {
+            return Err(PathError::Invalid);
+        }
+
+        let mut relative = PathBuf::new();
+        for component in Path::new(OsStr::from_bytes(&decoded)).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(PathError::Forbidden);
+                    }
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        let path = self.root.join(relative);
+
+        if !self.follow_symlinks {
+            // NOTE: the parts of the path that don't exist yet can't be symlinks, so it is
+            // enough to check where the longest existing prefix really points to.
+            let existing = path
+                .ancestors()
+                .find(|ancestor| ancestor.symlink_metadata().is_ok())
+                .ok_or(PathError::NotFound)?;
+            let canonical = existing.canonicalize().map_err(|_| PathError::NotFound)?;
+            if !canonical.starts_with(&self.root) {
+                return Err(PathError::Forbidden);
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Files that are being written to right now, so a second writer can be turned away instead of
+/// racing the first.
+#[derive(Debug, Default)]
+pub(crate) struct WriteLocks {
+    paths: Mutex<HashSet<PathBuf>>,
+}
+
+impl WriteLocks {
+    /// Locks `path` until the returned guard is dropped, `None` if it is locked already.
+    pub(crate) fn try_lock(&self, path: &Path) -> Option<WriteLock<'_>> {
+        let mut paths = self.paths.lock().unwrap_or_else(PoisonError::into_inner);
+        if !paths.insert(path.to_path_buf()) {
+            return None;
+        }
+        Some(WriteLock {
+            locks: self,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+pub(crate) struct WriteLock<'a> {
+    locks: &'a WriteLocks,
+    path: PathBuf,
+}
+
+impl Drop for WriteLock<'_> {
+    fn drop(&mut self) {
+        self.locks
+            .paths
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.path);
+    }
+}
+
+/// Whether `name` is one of the temporary files `write_atomically` writes to before renaming
+/// them into place, `.name.pid-count.tmp`.
+pub(crate) fn is_temporary_file(name: &str) -> bool {
+    let Some(stem) = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+    else {
+        return false;
+    };
+    let Some((file_name, unique)) = stem.rsplit_once('.') else {
+        return false;
+    };
+    let is_number = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    !file_name.is_empty()
+        && unique
+            .split_once('-')
+            .is_some_and(|(pid, count)| is_number(pid) && is_number(count))
+}
+
+/// Replaces `path` with everything read from `contents`, returning how many bytes that was.
+/// The data goes to a temporary file next to `path` that is synced and then renamed over it,
+/// so readers see either the old file or the complete new one, even across a crash.
+pub(crate) fn write_atomically(path: &Path, mut contents: impl Read) -> io::Result<u64> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(io::ErrorKind::InvalidInput.into());
+    };
+    // NOTE: the temporary file has to be in the same directory, renames across file systems
+    // are not atomic.
+    let tmp_path = dir.join(format!(
+        ".{}.{}-{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut tmp_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+
+    let written = (|| {
+        if let Ok(metadata) = fs::metadata(path) {
+            tmp_file.set_permissions(metadata.permissions())?;
+        }
+        let written = io::copy(&mut contents, &mut tmp_file)?;
+        tmp_file.flush()?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(written)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return written;
+    }
+    // NOTE: the rename itself is only durable once the directory is synced.
+    File::open(dir)?.sync_all()?;
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// A directory of its own under the system's temporary directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> io::Result<Self> {
+            static COUNTER: AtomicU64 = AtomicU64::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "files-test-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir(&path)?;
+            Ok(Self(path.canonicalize()?))
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn served(dir: &TempDir, follow_symlinks: bool) -> anyhow::Result<ServedDirectory> {
+        ServedDirectory::new(dir.path(), follow_symlinks)
+    }
+
+    #[test]
+    fn resolves_paths_under_the_root() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let served = served(&dir, true)?;
+        assert_eq!(served.resolve("a.txt"), Ok(dir.path().join("a.txt")));
+        assert_eq!(served.resolve("a/./b/../c"), Ok(dir.path().join("a/c")));
+        assert_eq!(served.resolve("a%2Fb"), Ok(dir.path().join("a/b")));
+        assert_eq!(served.resolve("a%20b"), Ok(dir.path().join("a b")));
+        // NOTE: a leading slash is just another separator, not the file system's root.
+        assert_eq!(
+            served.resolve("/etc/passwd"),
+            Ok(dir.path().join("etc/passwd"))
+        );
+        assert_eq!(
+            served.resolve("//etc/passwd"),
+            Ok(dir.path().join("etc/passwd"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_climb_above_the_root() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let served = served(&dir, true)?;
+        for url_path in [
+            "..",
+            "../etc/passwd",
+            "a/../../etc/passwd",
+            "%2e%2e%2fetc/passwd",
+            "%2E%2E/etc/passwd",
+            "a%2f..%2f..%2fetc",
+            "..%2F..%2F",
+        ] {
+            assert_eq!(
+                served.resolve(url_path),
+                Err(PathError::Forbidden),
+                "{url_path}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_paths() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let served = served(&dir, true)?;
+        for url_path in ["a%00.txt", "%00", "a%4", "%4", "%zz", "a%g0b", "%"] {
+            assert_eq!(
+                served.resolve(url_path),
+                Err(PathError::Invalid),
+                "{url_path}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_upload_targets_that_dont_exist_yet() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        for follow_symlinks in [true, false] {
+            let served = served(&dir, follow_symlinks)?;
+            assert_eq!(
+                served.resolve("new/dir/file.txt"),
+                Ok(dir.path().join("new/dir/file.txt"))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn follows_symlinks_out_of_the_root_only_when_allowed() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        let outside = TempDir::new()?;
+        fs::write(outside.path().join("secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out"))?;
+        fs::create_dir(dir.path().join("inside"))?;
+        std::os::unix::fs::symlink(dir.path().join("inside"), dir.path().join("in"))?;
+
+        let confined = served(&dir, false)?;
+        let escape = confined.resolve("out/secret.txt");
+        assert_eq!(escape, Err(PathError::Forbidden));
+        assert_eq!(escape.map_err(|err| err.status_code()), Err(403));
+        assert_eq!(confined.resolve("out/new.txt"), Err(PathError::Forbidden));
+        assert_eq!(
+            confined.resolve("in/file.txt"),
+            Ok(dir.path().join("in/file.txt"))
+        );
+
+        let following = served(&dir, true)?;
+        assert_eq!(
+            following.resolve("out/secret.txt"),
+            Ok(dir.path().join("out/secret.txt"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn recognizes_temporary_files() {
+        for name in [".a.txt.123-0.tmp", ".a.1-22.tmp", "...1-2.tmp"] {
+            assert!(is_temporary_file(name), "{name}");
+        }
+        for name in [
+            "a.txt",
+            ".hidden",
+            ".a.tmp",
+            "a.txt.1-2.tmp",
+            ".a.txt.1-x.tmp",
+            ".a.txt.-2.tmp",
+            ".a.txt.12.tmp",
+            ".1-2.tmp",
+        ] {
+            assert!(!is_temporary_file(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn listings_leave_temporary_files_out() -> anyhow::Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        fs::write(dir.path().join(".a.txt.1-2.tmp"), "partial")?;
+        let listing = listing::render(dir.path(), "/files/", listing::ListingFormat::Json)?;
+        let listing = String::from_utf8(listing)?;
+        assert!(listing.contains("\"a.txt\""), "{listing}");
+        assert!(!listing.contains(".tmp"), "{listing}");
+        Ok(())
+    }
+
+    /// The names in `dir`, sorted.
+    fn file_names(dir: &TempDir) -> io::Result<Vec<String>> {
+        let mut names = fs::read_dir(dir.path())?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    /// Yields some data, then fails.
+    struct Broken(bool);
+
+    impl Read for Broken {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if std::mem::replace(&mut self.0, true) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "broken"));
+            }
+            buf[..7].copy_from_slice(b"partial");
+            Ok(7)
+        }
+    }
+
+    #[test]
+    fn writes_files_atomically() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("a.txt");
+        assert_eq!(write_atomically(&path, &b"first"[..])?, 5);
+        assert_eq!(fs::read(&path)?, b"first");
+        assert_eq!(write_atomically(&path, &b"second"[..])?, 6);
+        assert_eq!(fs::read(&path)?, b"second");
+        assert_eq!(file_names(&dir)?, vec!["a.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_writes_leave_the_file_alone() -> io::Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "original")?;
+        let err = write_atomically(&path, Broken(false)).err();
+        assert_eq!(
+            err.map(|err| err.kind()),
+            Some(io::ErrorKind::ConnectionReset)
+        );
+        assert_eq!(fs::read(&path)?, b"original");
+        assert_eq!(file_names(&dir)?, vec!["a.txt"]);
+
+        let missing = dir.path().join("missing/a.txt");
+        let err = write_atomically(&missing, &b"data"[..]).err();
+        assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::NotFound));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_permissions_of_replaced_files() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("script.sh");
+        fs::write(&path, "#!/bin/sh")?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750))?;
+        write_atomically(&path, &b"#!/bin/sh\necho hi"[..])?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o750);
+        Ok(())
+    }
+
+    #[test]
+    fn write_locks_are_exclusive_until_dropped() {
+        let locks = WriteLocks::default();
+        let a = Path::new("/served/a.txt");
+        let lock = locks.try_lock(a);
+        assert!(lock.is_some());
+        assert!(locks.try_lock(a).is_none());
+        assert!(locks.try_lock(Path::new("/served/b.txt")).is_some());
+        drop(lock);
+        assert!(locks.try_lock(a).is_some());
+    }
+}
@@ -0,0 +1,304 @@
+use std::{fmt::Write, fs, path::Path, time::SystemTime};
+
+use crate::http::{date::format_rfc3339, encoding::parse_qvalue, HeadersV2};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ListingFormat {
+    Html,
+    Json,
+}
+
+impl ListingFormat {
+    /// The format the client's `Accept` prefers. JSON has to outweigh HTML to be picked, HTML
+    /// is what browsers get and what everyone else gets by default.
+    pub(crate) fn negotiate(headers: Option<&HeadersV2>) -> Self {
+        let Some(headers) = headers.filter(|headers| headers.contains(b"Accept")) else {
+            return ListingFormat::Html;
+        };
+        let media_ranges = headers
+            .get_list(b"Accept")
+            .filter_map(parse_media_range)
+            .collect::<Vec<_>>();
+        if media_range_weight(&media_ranges, "application/json")
+            > media_range_weight(&media_ranges, "text/html")
+        {
+            ListingFormat::Json
+        } else {
+            ListingFormat::Html
+        }
+    }
+}
+
+/// `type/subtype` of an element of `Accept` and its weight in thousandths, `None` when the
+/// element is malformed.
+fn parse_media_range(element: &str) -> Option<(&str, u16)> {
+    let mut params = element.split(';');
+    let media_range = params.next()?.trim();
+    if !media_range.contains('/') {
+        return None;
+    }
+    let mut weight = 1000;
+    for param in params {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("q") {
+            weight = parse_qvalue(value.trim())?;
+        }
+    }
+    Some((media_range, weight))
+}
+
+/// Weight of `media_type` as given by the most specific media range matching it, RFC 9110
+/// section 12.5.1. `0` when none does.
+fn media_range_weight(media_ranges: &[(&str, u16)], media_type: &str) -> u16 {
+    let Some((top, _)) = media_type.split_once('/') else {
+        return 0;
+    };
+    let type_wildcard = format!("{top}/*");
+    [media_type, type_wildcard.as_str(), "*/*"]
+        .iter()
+        .find_map(|candidate| {
+            media_ranges
+                .iter()
+                .find(|(media_range, _)| media_range.eq_ignore_ascii_case(candidate))
+        })
+        .map_or(0, |(_, weight)| *weight)
+}
+
+struct Entry {
+    name: String,
+    kind: &'static str,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn is_dir(&self) -> bool {
+        self.kind == "directory"
+    }
+}
+
+fn read_entries(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // NOTE: names that aren't UTF-8 can't be put in a JSON string or a link we could
+        // resolve again later, so they are left out.
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        // NOTE: uploads in progress, the file shows up under its own name once complete.
+        if super::is_temporary_file(&name) {
+            continue;
+        }
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        // NOTE: symlinks are listed as what they point to, a dangling one as a symlink.
+        let metadata = fs::metadata(entry.path()).or_else(|_| entry.metadata());
+        let Ok(metadata) = metadata else {
+            continue;
+        };
+        let kind = if metadata.is_dir() {
+            "directory"
+        } else if metadata.is_file() {
+            "file"
+        } else if file_type.is_symlink() {
+            "symlink"
+        } else {
+            "other"
+        };
+        entries.push(Entry {
+            name,
+            kind,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| {
+        b.is_dir()
+            .cmp(&a.is_dir())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(entries)
+}
+
+/// Renders the contents of `dir`. `url_path` is the path the listing was requested under and
+/// is only used as the title; links to the entries are relative to it.
+pub(crate) fn render(
+    dir: &Path,
+    url_path: &str,
+    format: ListingFormat,
+) -> std::io::Result<Vec<u8>> {
+    let entries = read_entries(dir)?;
+    let rendered = match format {
+        ListingFormat::Json => render_json(&entries),
+        ListingFormat::Html => render_html(&entries, url_path),
+    };
+    Ok(rendered.into_bytes())
+}
+
+fn render_json(entries: &[Entry]) -> String {
+    let mut out = String::from("[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let modified = match entry.modified {
+            Some(modified) => format!("\"{}\"", format_rfc3339(modified)),
+            None => "null".to_string(),
+        };
+        let _ = write!(
+            out,
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+            escape_json(&entry.name),
+            entry.kind,
+            entry.size,
+            modified
+        );
+    }
+    out.push(']');
+    out
+}
+
+fn render_html(entries: &[Entry], url_path: &str) -> String {
+    let title = escape_html(url_path);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<tr><th>Name</th><th>Type</th><th>Size</th><th>Modified</th></tr>\n"
+    );
+    for entry in entries.iter() {
+        let suffix = if entry.is_dir() { "/" } else { "" };
+        let modified = entry.modified.map(format_rfc3339).unwrap_or_default();
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"{href}{suffix}\">{name}{suffix}</a></td><td>{kind}</td><td>{size}</td><td>{modified}</td></tr>",
+            href = escape_html(&percent_encode(&entry.name)),
+            name = escape_html(&entry.name),
+            kind = entry.kind,
+            size = entry.size,
+        );
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+fn escape_json(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_html(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Encodes everything but the unreserved characters of RFC 3986, so a file name can be used as
+/// a single path segment.
+fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{b:02X}");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn negotiate_with(accept: &str) -> ListingFormat {
+        let mut headers = HeadersV2::new();
+        headers.append("Accept", accept.to_string());
+        ListingFormat::negotiate(Some(&headers))
+    }
+
+    #[test]
+    fn defaults_to_html() {
+        assert_eq!(ListingFormat::negotiate(None), ListingFormat::Html);
+        assert_eq!(negotiate_with("*/*"), ListingFormat::Html);
+        assert_eq!(negotiate_with("image/png"), ListingFormat::Html);
+        assert_eq!(
+            negotiate_with("text/html,application/xhtml+xml,*/*;q=0.8"),
+            ListingFormat::Html
+        );
+    }
+
+    #[test]
+    fn picks_json_when_it_outweighs_html() {
+        assert_eq!(negotiate_with("application/json"), ListingFormat::Json);
+        assert_eq!(
+            negotiate_with("application/json, text/html;q=0.9"),
+            ListingFormat::Json
+        );
+        assert_eq!(
+            negotiate_with("application/*, */*;q=0.1"),
+            ListingFormat::Json
+        );
+        assert_eq!(negotiate_with("APPLICATION/JSON"), ListingFormat::Json);
+    }
+
+    #[test]
+    fn weighs_relative_qvalues() {
+        assert_eq!(
+            negotiate_with("text/html, application/json;q=0.1"),
+            ListingFormat::Html
+        );
+        assert_eq!(
+            negotiate_with("text/html;q=0.5, application/json;q=0.5"),
+            ListingFormat::Html
+        );
+        assert_eq!(negotiate_with("application/json;q=0"), ListingFormat::Html);
+        assert_eq!(
+            negotiate_with("application/json;q=0.001, text/*;q=0"),
+            ListingFormat::Json
+        );
+    }
+
+    #[test]
+    fn most_specific_media_range_wins() {
+        assert_eq!(
+            negotiate_with("application/json;q=0.2, application/*;q=0.9, text/html;q=0.5"),
+            ListingFormat::Html
+        );
+        assert_eq!(
+            negotiate_with("*/*;q=0.9, text/html;q=0.1"),
+            ListingFormat::Json
+        );
+    }
+
+    #[test]
+    fn skips_malformed_media_ranges() {
+        assert_eq!(
+            negotiate_with("application/json;q=2, text/html;q=0.5"),
+            ListingFormat::Html
+        );
+        assert_eq!(negotiate_with("json, text/html;q=0.5"), ListingFormat::Html);
+    }
+}
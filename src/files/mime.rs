@@ -0,0 +1,340 @@
+use std::{borrow::Cow, collections::HashMap, path::Path};
+
+/// Media types by lowercase file extension.
+const BUILTIN_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("xml", "text/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// How many bytes from the start of a file `sniff` looks at.
+pub(crate) const SNIFF_LEN: usize = 512;
+
+/// Picks the `Content-Type` of served files: by extension, from the overrides first and the
+/// built-in table second, and by sniffing the content when the extension tells nothing.
+#[derive(Debug, Default)]
+pub(crate) struct MimeTypes {
+    overrides: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a mapping given as `ext=type`, e.g. `--mime-type wasm=application/wasm`.
+    pub(crate) fn with_override(mut self, mapping: &str) -> anyhow::Result<Self> {
+        let Some((ext, media_type)) = mapping.split_once('=') else {
+            anyhow::bail!("mime type mapping `{mapping}` must look like `ext=type`");
+        };
+        let ext = ext.trim().trim_start_matches('.');
+        let media_type = media_type.trim();
+        if ext.is_empty() || !media_type.contains('/') {
+            anyhow::bail!("mime type mapping `{mapping}` must look like `ext=type`");
+        }
+        self.overrides
+            .insert(ext.to_ascii_lowercase(), media_type.to_string());
+        Ok(self)
+    }
+
+    /// The media type for `path` going by its extension alone.
+    pub(crate) fn lookup(&self, path: &Path) -> Option<Cow<'static, str>> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        if let Some(media_type) = self.overrides.get(&ext) {
+            return Some(with_charset(media_type.clone()));
+        }
+        BUILTIN_TYPES
+            .iter()
+            .find(|(known, _)| *known == ext)
+            .map(|(_, media_type)| with_charset(*media_type))
+    }
+
+    /// The media type for `path`, falling back to sniffing `head`, the first bytes of the file,
+    /// when the extension is missing or unknown.
+    pub(crate) fn detect(&self, path: &Path, head: &[u8]) -> Cow<'static, str> {
+        self.lookup(path)
+            .unwrap_or_else(|| Cow::Borrowed(sniff(head)))
+    }
+}
+
+/// Appends a UTF-8 charset to textual types that don't name one already.
+fn with_charset(media_type: impl Into<Cow<'static, str>>) -> Cow<'static, str> {
+    let media_type = media_type.into();
+    let is_text = media_type.starts_with("text/")
+        || matches!(
+            media_type.as_ref(),
+            "application/json" | "application/manifest+json" | "image/svg+xml"
+        );
+    if is_text && !media_type.contains(';') {
+        Cow::Owned(format!("{media_type}; charset=utf-8"))
+    } else {
+        media_type
+    }
+}
+
+/// Guesses the media type from the leading bytes of a file, a small subset of the WHATWG MIME
+/// sniffing algorithm.
+fn sniff(head: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b\x08", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    if let Some((_, media_type)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return media_type;
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => {}
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+
+    let start = head
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(head.len());
+    let text = &head[start..];
+    let starts_with_ignore_case = |prefix: &[u8]| {
+        text.get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    };
+    if starts_with_ignore_case(b"<!doctype html") || starts_with_ignore_case(b"<html") {
+        return "text/html; charset=utf-8";
+    }
+    if starts_with_ignore_case(b"<?xml") {
+        return "text/xml; charset=utf-8";
+    }
+
+    // NOTE: the control characters text files don't contain, WHATWG MIME sniffing section 7.1.
+    let is_binary = head
+        .iter()
+        .any(|b| matches!(b, 0x00..=0x08 | 0x0b | 0x0e..=0x1a | 0x1c..=0x1f));
+    // NOTE: `head` may end in the middle of a character, that still counts as UTF-8.
+    let is_utf8 = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none(),
+    };
+    if !is_binary && is_utf8 {
+        "text/plain; charset=utf-8"
+    } else {
+        DEFAULT_TYPE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn lookup(types: &MimeTypes, path: &str) -> Option<String> {
+        types.lookup(Path::new(path)).map(Cow::into_owned)
+    }
+
+    #[test]
+    fn looks_up_extensions_case_insensitively() {
+        let types = MimeTypes::new();
+        assert_eq!(lookup(&types, "a.png").as_deref(), Some("image/png"));
+        assert_eq!(lookup(&types, "dir/A.PNG").as_deref(), Some("image/png"));
+        assert_eq!(
+            lookup(&types, "a.tar.gz").as_deref(),
+            Some("application/gzip")
+        );
+        assert_eq!(lookup(&types, "a.unknown"), None);
+        assert_eq!(lookup(&types, "Makefile"), None);
+        assert_eq!(lookup(&types, ".png"), None);
+    }
+
+    #[test]
+    fn adds_charset_to_text_types_only() {
+        let types = MimeTypes::new();
+        assert_eq!(
+            lookup(&types, "a.html").as_deref(),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(
+            lookup(&types, "a.json").as_deref(),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(
+            lookup(&types, "a.svg").as_deref(),
+            Some("image/svg+xml; charset=utf-8")
+        );
+        assert_eq!(
+            lookup(&types, "a.wasm").as_deref(),
+            Some("application/wasm")
+        );
+        assert_eq!(lookup(&types, "a.woff2").as_deref(), Some("font/woff2"));
+        assert_eq!(
+            with_charset("text/plain; charset=iso-8859-1"),
+            "text/plain; charset=iso-8859-1"
+        );
+    }
+
+    #[test]
+    fn overrides_take_precedence() -> anyhow::Result<()> {
+        let types = MimeTypes::new()
+            .with_override("js=application/javascript")?
+            .with_override(".DATA = application/x-data")?
+            .with_override("txt=text/plain; charset=iso-8859-1")?;
+        assert_eq!(
+            lookup(&types, "app.js").as_deref(),
+            Some("application/javascript")
+        );
+        assert_eq!(
+            lookup(&types, "a.data").as_deref(),
+            Some("application/x-data")
+        );
+        assert_eq!(
+            lookup(&types, "a.txt").as_deref(),
+            Some("text/plain; charset=iso-8859-1")
+        );
+        assert_eq!(
+            lookup(&types, "a.css").as_deref(),
+            Some("text/css; charset=utf-8")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_overrides() {
+        for mapping in ["js", "=text/plain", "js=", "js=text", "."] {
+            assert!(
+                MimeTypes::new().with_override(mapping).is_err(),
+                "{mapping}"
+            );
+        }
+    }
+
+    #[test]
+    fn detects_by_extension_before_content() {
+        let types = MimeTypes::new();
+        assert_eq!(
+            types.detect(Path::new("a.txt"), b"\x89PNG\r\n\x1a\n"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            types.detect(Path::new("a.unknown"), b"\x89PNG\r\n\x1a\n"),
+            "image/png"
+        );
+        assert_eq!(
+            types.detect(Path::new("README"), b"hello"),
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn sniffs_signatures() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), "image/jpeg");
+        assert_eq!(sniff(b"GIF89a\x01\0"), "image/gif");
+        assert_eq!(sniff(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff(b"PK\x03\x04\x14\0"), "application/zip");
+        assert_eq!(sniff(b"\x1f\x8b\x08\0"), "application/gzip");
+        assert_eq!(sniff(b"\0asm\x01\0\0\0"), "application/wasm");
+        assert_eq!(sniff(b"OggS\0\x02"), "audio/ogg");
+        // NOTE: signatures only count at the very start.
+        assert_eq!(sniff(b"\0\x89PNG\r\n\x1a\n"), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn sniffs_riff_and_ftyp_containers() {
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), "audio/wav");
+        assert_eq!(sniff(b"RIFF\x24\0\0\0AVI LIST"), DEFAULT_TYPE);
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42\0\0\0\0"), "video/mp4");
+        // NOTE: too short to hold the form type or brand.
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEB"), DEFAULT_TYPE);
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp4"), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn sniffs_markup() {
+        assert_eq!(
+            sniff(b"\n  <!DOCTYPE html><html>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(sniff(b"<HTML><body>"), "text/html; charset=utf-8");
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), "text/xml; charset=utf-8");
+    }
+
+    #[test]
+    fn sniffs_utf8_text() {
+        assert_eq!(sniff(b""), "text/plain; charset=utf-8");
+        assert_eq!(
+            sniff("h\u{e9}llo w\u{f6}rld\r\n\t".as_bytes()),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(sniff(b"hello\0world"), DEFAULT_TYPE);
+        assert_eq!(sniff(b"start of heading \x01"), DEFAULT_TYPE);
+        // NOTE: escape is not among the binary bytes, terminal output is text.
+        assert_eq!(sniff(b"\x1b[1mbold\x1b[0m"), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"latin-1 h\xe9llo"), DEFAULT_TYPE);
+    }
+
+    #[test]
+    fn sniffs_text_cut_off_mid_character() {
+        // NOTE: the sniffed window ends in the middle of `é` and of `€`.
+        let cut_off = |character: &str, keep: usize| {
+            let mut head = vec![b'a'; SNIFF_LEN - keep];
+            head.extend_from_slice(&character.as_bytes()[..keep]);
+            head
+        };
+        assert_eq!(sniff(&cut_off("\u{e9}", 1)), "text/plain; charset=utf-8");
+        assert_eq!(sniff(&cut_off("\u{20ac}", 1)), "text/plain; charset=utf-8");
+        assert_eq!(sniff(&cut_off("\u{20ac}", 2)), "text/plain; charset=utf-8");
+
+        // NOTE: a lead byte followed by one that can't continue it is no longer UTF-8.
+        let mut broken = cut_off("\u{e9}", 1);
+        broken[SNIFF_LEN - 2] = broken[SNIFF_LEN - 1];
+        broken[SNIFF_LEN - 1] = b'a';
+        assert_eq!(sniff(&broken), DEFAULT_TYPE);
+    }
+}
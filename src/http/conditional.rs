@@ -0,0 +1,396 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    date::{format_http_date, parse_http_date},
+    http_request::{HttpRequestV2, Method},
+    HeadersV2,
+};
+
+/// What a representation can be told apart by, RFC 9110 section 8.8.
+#[derive(Debug, Clone)]
+pub(crate) struct Validators {
+    /// Strong entity tag, quotes included.
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators of a file. The entity tag is made of its size and modification time, which
+    /// change on every write without having to read the file.
+    pub(crate) fn for_file(metadata: &std::fs::Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let mtime = last_modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            etag: format!("\"{:x}-{:x}\"", metadata.len(), mtime.as_nanos()),
+            last_modified,
+        }
+    }
+
+    /// Adds `ETag` and `Last-Modified` to `header`.
+    pub(crate) fn append_to(&self, header: &mut HeadersV2) {
+        header.append("ETag", self.etag.clone());
+        if let Some(last_modified) = self.last_modified {
+            header.append("Last-Modified", format_http_date(last_modified));
+        }
+    }
+
+    /// `Last-Modified` has a resolution of a second, so that is what dates compare at.
+    fn last_modified_secs(&self) -> Option<u64> {
+        self.last_modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Precondition {
+    /// Go on with the request.
+    Passed,
+    /// Answer with `304`, the client's copy is current.
+    NotModified,
+    /// Answer with `412`.
+    Failed,
+}
+
+/// Entity tags the client listed in `If-Match` or `If-None-Match`.
+enum EntityTags<'a> {
+    Any,
+    List(Vec<&'a str>),
+}
+
+impl<'a> EntityTags<'a> {
+    fn from_header(headers: &'a HeadersV2, name: &'a [u8]) -> Option<Self> {
+        if !headers.contains(name) {
+            return None;
+        }
+        let tags = headers.get_list(name).collect::<Vec<_>>();
+        if tags.contains(&"*") {
+            return Some(EntityTags::Any);
+        }
+        Some(EntityTags::List(tags))
+    }
+
+    /// Strong comparison needs both tags to be strong, weak comparison ignores the `W/`,
+    /// RFC 9110 section 8.8.3.2.
+    fn matches(&self, current: Option<&Validators>, weak: bool) -> bool {
+        let Some(current) = current else {
+            return false;
+        };
+        match self {
+            EntityTags::Any => true,
+            EntityTags::List(tags) => tags.iter().any(|tag| match tag.strip_prefix("W/") {
+                Some(tag) => weak && *tag == current.etag,
+                None => *tag == current.etag,
+            }),
+        }
+    }
+}
+
+fn header_date(headers: &HeadersV2, name: &[u8]) -> Option<u64> {
+    let value = std::str::from_utf8(headers.get(name)?).ok()?;
+    let date = parse_http_date(value)?;
+    Some(date.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Evaluates the request's preconditions in the order of RFC 9110 section 13.2.2. `current` are
+/// the validators of the target, `None` when it doesn't exist.
+pub(crate) fn evaluate(req: &HttpRequestV2, current: Option<&Validators>) -> Precondition {
+    let Some(headers) = req.headers.as_ref() else {
+        return Precondition::Passed;
+    };
+    let is_read = matches!(req.method, Method::Get | Method::Head);
+
+    if let Some(tags) = EntityTags::from_header(headers, b"If-Match") {
+        if !tags.matches(current, false) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = header_date(headers, b"If-Unmodified-Since") {
+        // NOTE: ignored when there is no modification date to compare with, RFC 9110
+        // section 13.1.4.
+        let modified = current
+            .and_then(Validators::last_modified_secs)
+            .is_some_and(|modified| modified > since);
+        if modified {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(tags) = EntityTags::from_header(headers, b"If-None-Match") {
+        if tags.matches(current, true) {
+            if is_read {
+                return Precondition::NotModified;
+            }
+            return Precondition::Failed;
+        }
+    } else if is_read {
+        if let Some(since) = header_date(headers, b"If-Modified-Since") {
+            let unmodified = current
+                .and_then(Validators::last_modified_secs)
+                .is_some_and(|modified| modified <= since);
+            if unmodified {
+                return Precondition::NotModified;
+            }
+        }
+    }
+    Precondition::Passed
+}
+
+/// Whether a `Range` may be honored, which `If-Range` only allows while the client's copy is
+/// still current, RFC 9110 section 13.1.5.
+pub(crate) fn if_range_allows(req: &HttpRequestV2, current: &Validators) -> bool {
+    let Some(value) = req
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(b"If-Range"))
+    else {
+        return true;
+    };
+    let Ok(value) = std::str::from_utf8(value) else {
+        return false;
+    };
+    let value = value.trim();
+    if value.starts_with('"') {
+        return value == current.etag;
+    }
+    if value.starts_with("W/") {
+        return false;
+    }
+    match (parse_http_date(value), current.last_modified_secs()) {
+        (Some(date), Some(modified)) => date
+            .duration_since(UNIX_EPOCH)
+            .is_ok_and(|since_epoch| since_epoch.as_secs() == modified),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{
+        request_parser::{ParseStatus, RequestParser},
+        HttpError,
+    };
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    const ETAG: &str = "\"a-1\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    }
+
+    fn current() -> Validators {
+        Validators {
+            etag: ETAG.to_string(),
+            last_modified: Some(modified()),
+        }
+    }
+
+    fn date(offset_secs: i64) -> String {
+        format_http_date(match offset_secs {
+            0.. => modified() + Duration::from_secs(offset_secs.unsigned_abs()),
+            _ => modified() - Duration::from_secs(offset_secs.unsigned_abs()),
+        })
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Result<HttpRequestV2, HttpError> {
+        let mut head = format!("{method} /file HTTP/1.1\r\n");
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        match RequestParser::new(0).parse(&mut BytesMut::from(head.as_str()))? {
+            ParseStatus::Complete(request) => Ok(request),
+            ParseStatus::Incomplete => Err(HttpError::ConnectionClosed),
+        }
+    }
+
+    fn evaluated(
+        method: &str,
+        headers: &[(&str, &str)],
+        current: Option<&Validators>,
+    ) -> Result<Precondition, HttpError> {
+        Ok(evaluate(&request(method, headers)?, current))
+    }
+
+    #[test]
+    fn passes_without_preconditions() -> Result<(), HttpError> {
+        assert_eq!(
+            evaluated("GET", &[], Some(&current()))?,
+            Precondition::Passed
+        );
+        assert_eq!(evaluated("PUT", &[], None)?, Precondition::Passed);
+        Ok(())
+    }
+
+    #[test]
+    fn if_match_takes_precedence_over_if_unmodified_since() -> Result<(), HttpError> {
+        let current = current();
+        let earlier = date(-60);
+        let later = date(60);
+        assert_eq!(
+            evaluated(
+                "PUT",
+                &[("If-Match", ETAG), ("If-Unmodified-Since", &earlier)],
+                Some(&current)
+            )?,
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluated(
+                "PUT",
+                &[("If-Match", "\"b-2\""), ("If-Unmodified-Since", &later)],
+                Some(&current)
+            )?,
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-Unmodified-Since", &earlier)], Some(&current))?,
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-Unmodified-Since", &date(0))], Some(&current))?,
+            Precondition::Passed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn if_match_needs_an_existing_target() -> Result<(), HttpError> {
+        assert_eq!(
+            evaluated("PUT", &[("If-Match", "*")], Some(&current()))?,
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-Match", "*")], None)?,
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-Match", ETAG)], None)?,
+            Precondition::Failed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn if_none_match_any_prevents_overwrites() -> Result<(), HttpError> {
+        assert_eq!(
+            evaluated("PUT", &[("If-None-Match", "*")], Some(&current()))?,
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-None-Match", "*")], None)?,
+            Precondition::Passed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn weak_tags_only_match_if_none_match() -> Result<(), HttpError> {
+        let current = current();
+        let weak = format!("W/{ETAG}");
+        assert_eq!(
+            evaluated("GET", &[("If-None-Match", &weak)], Some(&current))?,
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluated(
+                "HEAD",
+                &[("If-None-Match", "\"b-2\", W/\"a-1\"")],
+                Some(&current)
+            )?,
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluated("DELETE", &[("If-None-Match", &weak)], Some(&current))?,
+            Precondition::Failed
+        );
+        assert_eq!(
+            evaluated("PUT", &[("If-Match", &weak)], Some(&current))?,
+            Precondition::Failed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() -> Result<(), HttpError> {
+        let current = current();
+        let now = date(0);
+        assert_eq!(
+            evaluated("GET", &[("If-Modified-Since", &now)], Some(&current))?,
+            Precondition::NotModified
+        );
+        assert_eq!(
+            evaluated(
+                "GET",
+                &[("If-None-Match", "\"b-2\""), ("If-Modified-Since", &now)],
+                Some(&current)
+            )?,
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluated("GET", &[("If-Modified-Since", &date(-60))], Some(&current))?,
+            Precondition::Passed
+        );
+        // NOTE: only reads are answered with 304.
+        assert_eq!(
+            evaluated("PUT", &[("If-Modified-Since", &now)], Some(&current))?,
+            Precondition::Passed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_unparsable_dates() -> Result<(), HttpError> {
+        let current = current();
+        assert_eq!(
+            evaluated("GET", &[("If-Modified-Since", "yesterday")], Some(&current))?,
+            Precondition::Passed
+        );
+        assert_eq!(
+            evaluated(
+                "PUT",
+                &[("If-Unmodified-Since", "yesterday")],
+                Some(&current)
+            )?,
+            Precondition::Passed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn if_range_allows_only_a_current_copy() -> Result<(), HttpError> {
+        let current = current();
+        let allows = |value: &str| -> Result<bool, HttpError> {
+            Ok(if_range_allows(
+                &request("GET", &[("If-Range", value)])?,
+                &current,
+            ))
+        };
+        assert!(if_range_allows(&request("GET", &[])?, &current));
+        assert!(allows(ETAG)?);
+        assert!(!allows("\"b-2\"")?);
+        assert!(!allows(&format!("W/{ETAG}"))?);
+        assert!(allows(&date(0))?);
+        assert!(!allows(&date(-60))?);
+        assert!(!allows(&date(60))?);
+        assert!(!allows("yesterday")?);
+        Ok(())
+    }
+
+    #[test]
+    fn if_range_dates_need_a_modification_date() -> Result<(), HttpError> {
+        let current = Validators {
+            last_modified: None,
+            ..current()
+        };
+        assert!(!if_range_allows(
+            &request("GET", &[("If-Range", &date(0))])?,
+            &current
+        ));
+        Ok(())
+    }
+}
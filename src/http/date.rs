@@ -0,0 +1,229 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Broken down UTC time, all we need to render timestamps without pulling in a date crate.
+#[derive(Debug, PartialEq, Eq)]
+struct DateTime {
+    /// Days since Sunday.
+    weekday: u32,
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    fn from_system_time(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        let days = secs.div_euclid(86_400);
+        let secs_of_day = secs.rem_euclid(86_400) as u32;
+
+        // NOTE: "civil_from_days" from Howard Hinnant's date algorithms, shifting the year to
+        // start in March so the leap day is the last day of the year.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            // NOTE: the epoch was a Thursday.
+            weekday: (days + 4).rem_euclid(7) as u32,
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day % 3600 / 60,
+            second: secs_of_day % 60,
+        }
+    }
+
+    /// Seconds since the epoch, the inverse of `from_system_time` ("days_from_civil").
+    fn unix_timestamp(&self) -> i64 {
+        let month = i64::from(self.month);
+        let year = if month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let shifted_month = (month + 9) % 12;
+        let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+}
+
+/// `2024-06-01T12:00:00Z`, see RFC 3339.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let t = DateTime::from_system_time(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT`, the IMF-fixdate of RFC 9110 section 5.6.7.
+pub(crate) fn format_http_date(time: SystemTime) -> String {
+    let t = DateTime::from_system_time(time);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[t.weekday as usize],
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+/// Parses an HTTP-date in any of the three formats recipients have to accept, RFC 9110 section
+/// 5.6.7. The weekday is not checked against the date.
+pub(crate) fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let tokens = value.split_ascii_whitespace().collect::<Vec<_>>();
+    let (day, month, year, time) = match tokens.as_slice() {
+        // IMF-fixdate: `Sun, 06 Nov 1994 08:49:37 GMT`
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        // RFC 850: `Sunday, 06-Nov-94 08:49:37 GMT`
+        [_, date, time, "GMT"] => {
+            let mut parts = date.split('-');
+            let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+            if parts.next().is_some() || year.len() != 2 {
+                return None;
+            }
+            // NOTE: two digit years are taken to be in the past 70 or next 30 years of 2000,
+            // close enough to the "50 years in the future" rule for file timestamps.
+            let year = year.parse::<i64>().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        }
+        // asctime: `Sun Nov  6 08:49:37 1994`
+        [_, month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+
+    let mut time = time.split(':');
+    let mut time_part = || -> Option<u32> {
+        let part = time.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let (hour, minute, second) = (time_part()?, time_part()?, time_part()?);
+    if time.next().is_some() {
+        return None;
+    }
+    // NOTE: HTTP-dates have four digit years, anything else could overflow the conversion.
+    if !(1..=9999).contains(&year) {
+        return None;
+    }
+    let month = MONTHS.iter().position(|name| *name == month)? as u32 + 1;
+    let date_time = DateTime {
+        weekday: 0,
+        year,
+        month,
+        day: day.parse().ok()?,
+        hour,
+        minute,
+        second,
+    };
+
+    let timestamp = date_time.unix_timestamp();
+    let time = if timestamp >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(timestamp as u64))?
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(timestamp.unsigned_abs()))?
+    };
+    // NOTE: out of range fields like `31 Feb` or `25:00:00` don't survive the round trip.
+    let round_trip = DateTime::from_system_time(time);
+    if (round_trip.year, round_trip.month, round_trip.day)
+        != (date_time.year, date_time.month, date_time.day)
+        || (round_trip.hour, round_trip.minute, round_trip.second) != (hour, minute, second)
+    {
+        return None;
+    }
+    Some(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`, the example of RFC 9110 section 5.6.7.
+    fn example() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784_111_777)
+    }
+
+    #[test]
+    fn parses_all_three_formats() {
+        for value in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(value), Some(example()), "{value}");
+        }
+    }
+
+    #[test]
+    fn formats_and_parses_back() {
+        assert_eq!(format_http_date(example()), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_rfc3339(example()), "1994-11-06T08:49:37Z");
+        for secs in [0, 951_782_400, 4_107_542_399, 253_402_300_799] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse_http_date(&format_http_date(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_fields() {
+        for value in [
+            "Sun, 31 Feb 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 25:00:00 GMT",
+            "Sun, 06 Nov 1994 08:60:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 0 08:49:37 GMT",
+            "Sun, 06 Nov 10000 08:49:37 GMT",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_years() {
+        for value in [
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov -9223372036854775808 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 9223372036854775807",
+            "Sun, 4294967295 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{value}");
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use std::io::Read;
+
+use flate2::{
+    read::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use super::{HeadersV2, ACCEPT_ENCODING_HEADER};
+
+pub(crate) type BodyReader = Box<dyn Read + Send>;
+
+/// A content coding we can apply to response bodies, RFC 9110 section 8.4.1.
+pub(crate) trait Encoder: Send + Sync {
+    /// The token the coding goes by in `Accept-Encoding` and `Content-Encoding`.
+    fn name(&self) -> &'static str;
+
+    /// Changes the compression level, failing for levels the coding doesn't have.
+    fn set_level(&mut self, level: u32) -> anyhow::Result<()>;
+
+    /// Wraps `body` so that reading from it yields the encoded body.
+    fn encode(&self, body: BodyReader) -> std::io::Result<BodyReader>;
+}
+
+fn check_level(name: &str, level: u32, max: u32) -> anyhow::Result<u32> {
+    if level > max {
+        anyhow::bail!("{name} compression level must be between 0 and {max}, not {level}");
+    }
+    Ok(level)
+}
+
+pub(crate) struct Gzip {
+    level: u32,
+}
+
+impl Encoder for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn set_level(&mut self, level: u32) -> anyhow::Result<()> {
+        self.level = check_level(self.name(), level, 9)?;
+        Ok(())
+    }
+
+    fn encode(&self, body: BodyReader) -> std::io::Result<BodyReader> {
+        Ok(Box::new(GzEncoder::new(body, Compression::new(self.level))))
+    }
+}
+
+/// `deflate` is the zlib format of RFC 1950, not a raw deflate stream, RFC 9110 section 8.4.1.2.
+pub(crate) struct Deflate {
+    level: u32,
+}
+
+impl Encoder for Deflate {
+    fn name(&self) -> &'static str {
+        "deflate"
+    }
+
+    fn set_level(&mut self, level: u32) -> anyhow::Result<()> {
+        self.level = check_level(self.name(), level, 9)?;
+        Ok(())
+    }
+
+    fn encode(&self, body: BodyReader) -> std::io::Result<BodyReader> {
+        Ok(Box::new(ZlibEncoder::new(
+            body,
+            Compression::new(self.level),
+        )))
+    }
+}
+
+#[cfg(feature = "brotli")]
+pub(crate) struct Brotli {
+    quality: u32,
+}
+
+#[cfg(feature = "brotli")]
+impl Encoder for Brotli {
+    fn name(&self) -> &'static str {
+        "br"
+    }
+
+    fn set_level(&mut self, level: u32) -> anyhow::Result<()> {
+        self.quality = check_level(self.name(), level, 11)?;
+        Ok(())
+    }
+
+    fn encode(&self, body: BodyReader) -> std::io::Result<BodyReader> {
+        // NOTE: a 4 MB window, what the reference encoder uses by default.
+        const LG_WINDOW_SIZE: u32 = 22;
+        Ok(Box::new(brotli::CompressorReader::new(
+            body,
+            super::EIGHT_KB_IN_BYTES,
+            self.quality,
+            LG_WINDOW_SIZE,
+        )))
+    }
+}
+
+#[cfg(feature = "zstd")]
+pub(crate) struct Zstd {
+    level: u32,
+}
+
+#[cfg(feature = "zstd")]
+impl Encoder for Zstd {
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn set_level(&mut self, level: u32) -> anyhow::Result<()> {
+        // NOTE: levels above 19 need a window larger than the 8 MB decoders have to support
+        // for `Content-Encoding: zstd`, RFC 8878 section 3.
+        self.level = check_level(self.name(), level, 19)?;
+        Ok(())
+    }
+
+    fn encode(&self, body: BodyReader) -> std::io::Result<BodyReader> {
+        Ok(Box::new(zstd::stream::read::Encoder::new(
+            body,
+            self.level as i32,
+        )?))
+    }
+}
+
+/// The content codings responses may be encoded with, in our order of preference.
+pub(crate) struct EncoderRegistry {
+    encoders: Vec<Box<dyn Encoder>>,
+}
+
+impl EncoderRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            encoders: Vec::new(),
+        }
+    }
+
+    /// Adds `encoder`, replacing any encoder registered under the same name.
+    pub(crate) fn with<E>(mut self, encoder: E) -> Self
+    where
+        E: Encoder + 'static,
+    {
+        self.encoders.retain(|known| known.name() != encoder.name());
+        self.encoders.push(Box::new(encoder));
+        self
+    }
+
+    /// Sets the level of a registered coding from `name=level`, e.g.
+    /// `--compression-level gzip=9`.
+    pub(crate) fn with_level(mut self, mapping: &str) -> anyhow::Result<Self> {
+        let Some((name, level)) = mapping.split_once('=') else {
+            anyhow::bail!("compression level `{mapping}` must look like `coding=level`");
+        };
+        let level = level
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("compression level `{mapping}` is not a number"))?;
+        let Some(encoder) = self
+            .encoders
+            .iter_mut()
+            .find(|encoder| encoder.name().eq_ignore_ascii_case(name.trim()))
+        else {
+            anyhow::bail!("unknown content coding `{}`", name.trim());
+        };
+        encoder.set_level(level)?;
+        Ok(self)
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.encoders.iter().map(|encoder| encoder.name())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&dyn Encoder> {
+        self.encoders
+            .iter()
+            .find(|encoder| encoder.name() == name)
+            .map(|encoder| encoder.as_ref())
+    }
+}
+
+impl Default for EncoderRegistry {
+    /// Every coding compiled in, at levels suited to compressing responses on the fly: the
+    /// library defaults, except for brotli, whose default quality of 11 is meant for offline
+    /// compression and would make every response wait on it. Precompressed files can still use
+    /// that, see `--precompressed`.
+    fn default() -> Self {
+        let registry = Self::new();
+        #[cfg(feature = "brotli")]
+        let registry = registry.with(Brotli { quality: 5 });
+        #[cfg(feature = "zstd")]
+        let registry = registry.with(Zstd { level: 3 });
+        registry.with(Gzip { level: 6 }).with(Deflate { level: 6 })
+    }
+}
+
+/// Extension of precompressed sibling files in `coding`, e.g. `app.js.gz` for `gzip`.
+pub(crate) fn file_extension(coding: &str) -> Option<&'static str> {
+    match coding {
+        "gzip" => Some("gz"),
+        "br" => Some("br"),
+        "zstd" => Some("zst"),
+        _ => None,
+    }
+}
+
+/// Media types worth compressing by default. Most other formats, images, audio, video, fonts
+/// and archives, come compressed already.
+const DEFAULT_COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "application/manifest+json",
+    "image/svg+xml",
+];
+
+/// Which responses are worth compressing at all.
+#[derive(Debug, Clone)]
+pub(crate) struct CompressionPolicy {
+    /// Bodies shorter than this go out as is, compressing them saves less than the coding's
+    /// own overhead.
+    min_size: u64,
+    /// Media types to compress, either exact or `type/*`.
+    media_types: Vec<String>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            min_size: 256,
+            media_types: DEFAULT_COMPRESSIBLE_TYPES
+                .iter()
+                .map(|media_type| media_type.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub(crate) fn with_min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Replaces the media types to compress, e.g. `--compress-type text/* --compress-type
+    /// application/json`.
+    pub(crate) fn with_media_types<'a>(
+        mut self,
+        media_types: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<Self> {
+        self.media_types = media_types
+            .into_iter()
+            .map(|media_type| {
+                let media_type = media_type.trim().to_ascii_lowercase();
+                match media_type.split_once('/') {
+                    Some((top, sub)) if !top.is_empty() && !sub.is_empty() => Ok(media_type),
+                    _ => anyhow::bail!("`{media_type}` is not a media type like `text/*`"),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Whether a body of `len` bytes, `None` when unknown, may be compressed.
+    pub(crate) fn allows_len(&self, len: Option<u64>) -> bool {
+        match len {
+            Some(len) => len >= self.min_size,
+            None => true,
+        }
+    }
+
+    /// Whether a body with the `Content-Type` `content_type` may be compressed. Parameters such
+    /// as `charset` are ignored.
+    pub(crate) fn allows_type(&self, content_type: &[u8]) -> bool {
+        let Ok(content_type) = std::str::from_utf8(content_type) else {
+            return false;
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let Some((top, _)) = essence.split_once('/') else {
+            return false;
+        };
+        self.media_types.iter().any(|allowed| {
+            *allowed == essence
+                || allowed
+                    .strip_suffix("/*")
+                    .is_some_and(|allowed_top| allowed_top == top)
+        })
+    }
+}
+
+/// Outcome of negotiating a content coding against the client's `Accept-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Negotiated<'a> {
+    /// Encode the body with this coding.
+    Coding(&'a str),
+    /// Send the body as is.
+    Identity,
+    /// Neither any of our codings nor the unencoded body are acceptable to the client.
+    NotAcceptable,
+}
+
+/// Parses a `qvalue` into thousandths, RFC 9110 section 12.4.2.
+pub(crate) fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac = format!("{frac:0<3}").parse::<u16>().ok()?;
+    match int {
+        "0" => Some(frac),
+        "1" if frac == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// `coding` and its weight for every element of `Accept-Encoding`. Elements with a malformed
+/// weight are skipped.
+fn accepted_codings(headers: &HeadersV2) -> Vec<(&str, u16)> {
+    headers
+        .get_list(ACCEPT_ENCODING_HEADER.as_bytes())
+        .filter_map(|element| {
+            let mut params = element.split(';');
+            let coding = params.next()?.trim();
+            let mut weight = 1000;
+            for param in params {
+                let (name, value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    weight = parse_qvalue(value.trim())?;
+                }
+            }
+            Some((coding, weight))
+        })
+        .collect()
+}
+
+/// Picks the content coding for a response from `supported`, in our order of preference,
+/// following RFC 9110 section 12.5.3: the highest weight wins, `*` stands for every coding not
+/// listed explicitly, a weight of `0` rules a coding out, and the unencoded body stays
+/// acceptable unless ruled out explicitly or through `*`. Among equal weights our codings are
+/// preferred over sending the body as is.
+pub(crate) fn negotiate<'a>(
+    headers: Option<&HeadersV2>,
+    supported: impl IntoIterator<Item = &'a str>,
+) -> Negotiated<'a> {
+    let Some(headers) = headers.filter(|headers| headers.contains(b"Accept-Encoding")) else {
+        // NOTE: without `Accept-Encoding` any coding would do, but the body as is is what
+        // every client understands.
+        return Negotiated::Identity;
+    };
+    let accepted = accepted_codings(headers);
+    let weight_of = |coding: &str| {
+        accepted
+            .iter()
+            .find(|(accepted, _)| accepted.eq_ignore_ascii_case(coding))
+            .or_else(|| accepted.iter().find(|(accepted, _)| *accepted == "*"))
+            .map(|(_, weight)| *weight)
+    };
+
+    let mut best: Option<(&str, u16)> = None;
+    for coding in supported {
+        let Some(weight) = weight_of(coding).filter(|weight| *weight > 0) else {
+            continue;
+        };
+        match best {
+            Some((_, best_weight)) if best_weight >= weight => {}
+            _ => best = Some((coding, weight)),
+        }
+    }
+    let identity_weight = weight_of("identity").unwrap_or(1);
+    match best {
+        Some((coding, weight)) if weight >= identity_weight => Negotiated::Coding(coding),
+        _ if identity_weight > 0 => Negotiated::Identity,
+        Some((coding, _)) => Negotiated::Coding(coding),
+        None => Negotiated::NotAcceptable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SUPPORTED: [&str; 2] = ["br", "gzip"];
+
+    fn negotiate_with(accept_encoding: &[&str]) -> Negotiated<'static> {
+        let mut headers = HeadersV2::new();
+        for value in accept_encoding {
+            headers.append(ACCEPT_ENCODING_HEADER, value.to_string());
+        }
+        negotiate(Some(&headers), SUPPORTED)
+    }
+
+    #[test]
+    fn parses_qvalues() {
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("1.000"), Some(1000));
+        assert_eq!(parse_qvalue("0"), Some(0));
+        assert_eq!(parse_qvalue("0.5"), Some(500));
+        assert_eq!(parse_qvalue("0.05"), Some(50));
+        assert_eq!(parse_qvalue("0.001"), Some(1));
+        assert_eq!(parse_qvalue("0."), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_qvalues() {
+        for value in [
+            "", "1.001", "2", "0.0001", "-0.5", ".5", "0.5x", "0,5", "01", "1e0",
+        ] {
+            assert_eq!(parse_qvalue(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn without_accept_encoding_sends_identity() {
+        assert_eq!(negotiate(None, SUPPORTED), Negotiated::Identity);
+        assert_eq!(
+            negotiate(Some(&HeadersV2::new()), SUPPORTED),
+            Negotiated::Identity
+        );
+    }
+
+    #[test]
+    fn empty_accept_encoding_asks_for_identity() {
+        assert_eq!(negotiate_with(&[""]), Negotiated::Identity);
+    }
+
+    #[test]
+    fn picks_the_highest_weight() {
+        assert_eq!(negotiate_with(&["gzip"]), Negotiated::Coding("gzip"));
+        assert_eq!(
+            negotiate_with(&["br;q=0.5, gzip;q=0.8"]),
+            Negotiated::Coding("gzip")
+        );
+        assert_eq!(
+            negotiate_with(&["deflate, gzip;q=0.1"]),
+            Negotiated::Coding("gzip")
+        );
+        assert_eq!(negotiate_with(&["deflate"]), Negotiated::Identity);
+    }
+
+    #[test]
+    fn ties_go_to_our_preference_over_identity() {
+        assert_eq!(negotiate_with(&["gzip, br"]), Negotiated::Coding("br"));
+        assert_eq!(negotiate_with(&["gzip", "br"]), Negotiated::Coding("br"));
+        assert_eq!(
+            negotiate_with(&["identity, gzip"]),
+            Negotiated::Coding("gzip")
+        );
+        assert_eq!(
+            negotiate_with(&["identity, gzip;q=0.9"]),
+            Negotiated::Identity
+        );
+    }
+
+    #[test]
+    fn matches_codings_case_insensitively() {
+        assert_eq!(negotiate_with(&["GZIP"]), Negotiated::Coding("gzip"));
+        assert_eq!(
+            negotiate_with(&["Br;Q=0.1, gzip;q=0.05"]),
+            Negotiated::Coding("br")
+        );
+        assert_eq!(
+            negotiate_with(&["gzip;q=0.1, IDENTITY;Q=0"]),
+            Negotiated::Coding("gzip")
+        );
+    }
+
+    #[test]
+    fn wildcard_stands_for_unlisted_codings() {
+        assert_eq!(negotiate_with(&["*"]), Negotiated::Coding("br"));
+        assert_eq!(negotiate_with(&["br;q=0, *"]), Negotiated::Coding("gzip"));
+        assert_eq!(
+            negotiate_with(&["*;q=0.1, gzip;q=0.5"]),
+            Negotiated::Coding("gzip")
+        );
+    }
+
+    #[test]
+    fn zero_weight_rules_codings_out() {
+        assert_eq!(negotiate_with(&["gzip;q=0"]), Negotiated::Identity);
+        assert_eq!(negotiate_with(&["identity;q=0"]), Negotiated::NotAcceptable);
+        assert_eq!(
+            negotiate_with(&["identity;q=0, gzip;q=0.001"]),
+            Negotiated::Coding("gzip")
+        );
+        assert_eq!(negotiate_with(&["*;q=0"]), Negotiated::NotAcceptable);
+        assert_eq!(negotiate_with(&["*;q=0, identity"]), Negotiated::Identity);
+        assert_eq!(negotiate_with(&["*;q=0, gzip"]), Negotiated::Coding("gzip"));
+    }
+
+    #[test]
+    fn skips_elements_with_malformed_weights() {
+        assert_eq!(
+            negotiate_with(&["br;q=2, gzip;q=0.5"]),
+            Negotiated::Coding("gzip")
+        );
+        assert_eq!(negotiate_with(&["gzip;q=high"]), Negotiated::Identity);
+        assert_eq!(
+            negotiate_with(&["identity;q=x, *;q=0"]),
+            Negotiated::NotAcceptable
+        );
+    }
+
+    #[test]
+    fn policy_allows_listed_media_types() -> anyhow::Result<()> {
+        let policy =
+            CompressionPolicy::default().with_media_types(["text/*", "Application/JSON"])?;
+        assert!(policy.allows_type(b"text/plain"));
+        assert!(policy.allows_type(b"text/html; charset=utf-8"));
+        assert!(policy.allows_type(b"TEXT/CSS"));
+        assert!(policy.allows_type(b"application/json;charset=utf-8"));
+        assert!(policy.allows_type(b" application/json "));
+        assert!(!policy.allows_type(b"application/javascript"));
+        assert!(!policy.allows_type(b"textual/plain"));
+        assert!(!policy.allows_type(b"image/png"));
+        assert!(!policy.allows_type(b"text"));
+        assert!(!policy.allows_type(b""));
+        assert!(!policy.allows_type(b"text/\xff"));
+        Ok(())
+    }
+
+    #[test]
+    fn default_policy_skips_compressed_formats() {
+        let policy = CompressionPolicy::default();
+        assert!(policy.allows_type(b"text/css"));
+        assert!(policy.allows_type(b"image/svg+xml"));
+        assert!(!policy.allows_type(b"image/png"));
+        assert!(!policy.allows_type(b"application/zip"));
+    }
+
+    #[test]
+    fn rejects_malformed_media_types() {
+        for media_type in ["text", "/plain", "text/", ""] {
+            assert!(
+                CompressionPolicy::default()
+                    .with_media_types([media_type])
+                    .is_err(),
+                "{media_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn policy_allows_bodies_from_the_minimum_size() {
+        let policy = CompressionPolicy::default();
+        assert!(!policy.allows_len(Some(0)));
+        assert!(!policy.allows_len(Some(255)));
+        assert!(policy.allows_len(Some(256)));
+        // NOTE: streamed bodies of unknown length are assumed to be large.
+        assert!(policy.allows_len(None));
+
+        let policy = policy.with_min_size(0);
+        assert!(policy.allows_len(Some(0)));
+    }
+}
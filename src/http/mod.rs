@@ -1,119 +1,277 @@
 #![allow(unused_assignments)]
+pub(crate) mod conditional;
+pub(crate) mod date;
+pub(crate) mod encoding;
 pub(crate) mod http_request;
-use bytes::{Bytes, BytesMut};
-use lazy_static::lazy_static;
+pub(crate) mod range;
+pub(crate) mod request_body;
+pub(crate) mod request_parser;
+pub(crate) mod status_code;
+use bytes::Bytes;
 use std::{
-    collections::{HashMap, HashSet},
-    io::Write,
-    ops::{Deref, DerefMut},
+    borrow::Cow,
+    fs::File,
+    io::{IoSlice, Read, Write},
 };
 use thiserror::Error;
 
 pub(crate) const ACCEPT_ENCODING_HEADER: &str = "Accept-Encoding";
 pub(crate) const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
+pub(crate) const CONNECTION_HEADER: &str = "Connection";
 pub(crate) const EIGHT_KB_IN_BYTES: usize = 8192;
-
-lazy_static! {
-    pub(crate) static ref SUPPORTED_ENCODINGS: HashSet<&'static str> = {
-        let mut set = HashSet::new();
-        set.insert("gzip");
-        set
-    };
-}
+pub(crate) const DEFAULT_MAX_BODY_SIZE_IN_BYTES: usize = 10 * 1024 * 1024;
 
 #[derive(Error, Debug)]
 pub(crate) enum HttpError {
-    #[error("http request version parsing")]
+    #[error("malformed http version")]
     HttpVersionParseError,
-    #[error("Inner Error")]
-    Adhoc(&'static str),
+    #[error("http version not supported")]
+    UnsupportedHttpVersion,
+    #[error("method not implemented")]
+    UnknownMethod,
     #[error("io error")]
     IoErr(std::io::Error),
-    #[error("Utf8Error")]
+    #[error("request target is not valid utf-8")]
     Utf8Error(std::str::Utf8Error),
-    #[error("error parsing request")]
+    #[error("error parsing request: {0}")]
     RequestParsingError(&'static str),
-    #[error("Invalid Content Length")]
+    #[error("invalid content length")]
     InvalidContentLengthInRequest,
+    #[error("transfer encoding not implemented")]
+    UnsupportedTransferEncoding,
+    #[error("connection closed by peer")]
+    ConnectionClosed,
+    #[error("request body exceeds the maximum allowed size")]
+    PayloadTooLarge,
+    #[error("request line too long")]
+    UriTooLong,
+    #[error("request header section too large")]
+    HeaderSectionTooLarge,
 }
 
-#[derive(Debug)]
-pub(crate) struct Headers {
-    map: HashMap<String, String>,
-}
+impl HttpError {
+    /// Status code of the response the client should get for this error, `None` when there is
+    /// nobody left to respond to.
+    pub(crate) fn status_code(&self) -> Option<u16> {
+        let status_code = match self {
+            HttpError::ConnectionClosed | HttpError::IoErr(_) => return None,
+            HttpError::HttpVersionParseError
+            | HttpError::Utf8Error(_)
+            | HttpError::RequestParsingError(_)
+            | HttpError::InvalidContentLengthInRequest => 400,
+            HttpError::PayloadTooLarge => 413,
+            HttpError::UriTooLong => 414,
+            HttpError::HeaderSectionTooLarge => 431,
+            HttpError::UnknownMethod | HttpError::UnsupportedTransferEncoding => 501,
+            HttpError::UnsupportedHttpVersion => 505,
+        };
+        Some(status_code)
+    }
 
-#[derive(Debug)]
-pub(crate) struct HeadersV2 {
-    map: HashMap<Bytes, Bytes>,
-}
+    /// The `HttpError` behind `err` if it comes from reading a `RequestBody`.
+    pub(crate) fn wrapped_in(err: &std::io::Error) -> Option<&HttpError> {
+        err.get_ref()?.downcast_ref::<HttpError>()
+    }
 
-impl HeadersV2 {
-    pub(crate) fn new() -> Self {
-        Self {
-            map: HashMap::new(),
+    /// Recovers the `HttpError` a failed read of a `RequestBody` wraps, any other `io::Error`
+    /// becomes `HttpError::IoErr`.
+    pub(crate) fn from_io(err: std::io::Error) -> Self {
+        if HttpError::wrapped_in(&err).is_none() {
+            return HttpError::IoErr(err);
+        }
+        match err.into_inner().map(|inner| inner.downcast::<HttpError>()) {
+            Some(Ok(err)) => *err,
+            // NOTE: can't happen, checked right above.
+            _ => HttpError::ConnectionClosed,
         }
     }
+
+    /// Builds the response for this error, with the error message as a short plain text body
+    /// so the client can tell what it got wrong.
+    pub(crate) fn to_response(&self) -> Option<HttpResponse> {
+        let status_code = self.status_code()?;
+        let body = format!("{self}\n").into_bytes();
+        let mut header = HeadersV2::new();
+        header.append("Content-Type", "text/plain");
+        header.append("Content-Length", body.len().to_string());
+        Some(
+            HttpResponseBuilder::new(status_code)
+                .with_header(header)
+                .with_body(body)
+                .build(),
+        )
+    }
 }
 
-impl Deref for HeadersV2 {
-    type Target = HashMap<Bytes, Bytes>;
+/// Value of the `Connection` header sent back with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Connection {
+    KeepAlive,
+    Close,
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.map
+impl Connection {
+    fn header_value(&self) -> &'static [u8] {
+        match self {
+            Connection::KeepAlive => b"keep-alive",
+            Connection::Close => b"close",
+        }
     }
 }
 
-impl DerefMut for HeadersV2 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.map
-    }
+/// Header fields in the order they were added. Field names are compared case-insensitively and a
+/// name may occur more than once (`Set-Cookie`, or list based fields like `Accept` sent on
+/// several lines).
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HeadersV2 {
+    fields: Vec<(Bytes, Bytes)>,
 }
 
-impl Headers {
+impl HeadersV2 {
     pub(crate) fn new() -> Self {
-        Self {
-            map: HashMap::default(),
+        Self { fields: Vec::new() }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Adds a field after the existing ones, keeping any values already present for `key`.
+    pub(crate) fn append(&mut self, key: impl Into<Bytes>, val: impl Into<Bytes>) {
+        self.fields.push((key.into(), val.into()));
+    }
+
+    /// Sets `key` to `val`. The first field with that name keeps its position and takes the new
+    /// value, every other field with that name is dropped.
+    pub(crate) fn insert(&mut self, key: impl Into<Bytes>, val: impl Into<Bytes>) {
+        let key = key.into();
+        let val = val.into();
+        match self.position(&key) {
+            Some(pos) => {
+                self.fields[pos].1 = val;
+                let mut index = 0;
+                self.fields.retain(|(k, _)| {
+                    let keep = index <= pos || !k.eq_ignore_ascii_case(&key);
+                    index += 1;
+                    keep
+                });
+            }
+            None => self.fields.push((key, val)),
         }
     }
-}
 
-impl Deref for Headers {
-    type Target = HashMap<String, String>;
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        self.position(key).is_some()
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.map
+    /// First value of `key`.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Bytes> {
+        self.position(key).map(|pos| &self.fields[pos].1)
+    }
+
+    /// Every value of `key`, in the order they were added.
+    pub(crate) fn get_all<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a Bytes> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v)
+    }
+
+    /// The elements of a comma separated list field, across all the lines it was sent on.
+    /// Empty elements are skipped, see RFC 9110 section 5.6.1.
+    pub(crate) fn get_list<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = &'a str> + 'a {
+        self.get_all(key)
+            .filter_map(|val| std::str::from_utf8(val).ok())
+            .flat_map(|val| val.split(','))
+            .map(|element| element.trim())
+            .filter(|element| !element.is_empty())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&Bytes, &Bytes)> {
+        self.fields.iter().map(|(k, v)| (k, v))
     }
-}
 
-impl DerefMut for Headers {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.map
+    fn position(&self, key: &[u8]) -> Option<usize> {
+        self.fields
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))
     }
 }
 
 pub(crate) enum ContentTypeHttpResponse {
-    #[allow(dead_code)]
     Json(HttpResponse),
+    Html(HttpResponse),
     PlainText(HttpResponse),
     NoBody(HttpResponse),
-    File(HttpResponse),
+    /// Response with the contents of a file, of the given media type.
+    File(HttpResponse, Cow<'static, str>),
 }
 
 impl ContentTypeHttpResponse {
-    pub(crate) fn get_content_type_header_value(&self) -> Option<&'static str> {
+    pub(crate) fn get_content_type_header_value(&self) -> Option<&str> {
         match self {
             ContentTypeHttpResponse::Json(_) => Some("application/json"),
+            ContentTypeHttpResponse::Html(_) => Some("text/html; charset=utf-8"),
             ContentTypeHttpResponse::PlainText(_) => Some("text/plain"),
             ContentTypeHttpResponse::NoBody(_) => None,
-            ContentTypeHttpResponse::File(_) => Some("application/octet-stream"),
+            ContentTypeHttpResponse::File(_, content_type) => Some(content_type),
         }
     }
-    pub(crate) fn into_inner(self) -> HttpResponse {
-        match self {
+    /// The inner response with its `Content-Type` header set.
+    pub(crate) fn into_response(self) -> HttpResponse {
+        let content_type = self.get_content_type_header_value().map(str::to_string);
+        let mut response = match self {
             ContentTypeHttpResponse::Json(response) => response,
+            ContentTypeHttpResponse::Html(response) => response,
             ContentTypeHttpResponse::PlainText(response) => response,
             ContentTypeHttpResponse::NoBody(response) => response,
-            ContentTypeHttpResponse::File(response) => response,
+            ContentTypeHttpResponse::File(response, _) => response,
+        };
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert("Content-Type", content_type);
+        }
+        response
+    }
+}
+
+pub(crate) enum ResponseBody {
+    /// Body that is fully materialized in memory.
+    Full(Vec<u8>),
+    /// Body that is pulled from `reader` while the response is being written. When the length
+    /// is not known upfront it is sent with `Transfer-Encoding: chunked`.
+    Stream {
+        reader: Box<dyn Read + Send>,
+        content_length: Option<u64>,
+    },
+    /// The next `len` bytes of `file`, from its current position on. Kept as a `File` rather
+    /// than a boxed reader so `std::io::copy` can hand the transfer to the kernel with
+    /// `sendfile`/`copy_file_range` where it is available, and fall back to a buffered copy
+    /// where it is not.
+    File { file: File, len: u64 },
+}
+
+impl ResponseBody {
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        match self {
+            ResponseBody::Full(body) => Some(body.len() as u64),
+            ResponseBody::Stream { content_length, .. } => *content_length,
+            ResponseBody::File { len, .. } => Some(*len),
+        }
+    }
+}
+
+impl std::fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseBody::Full(body) => f.debug_tuple("Full").field(&body.len()).finish(),
+            ResponseBody::Stream { content_length, .. } => f
+                .debug_struct("Stream")
+                .field("content_length", content_length)
+                .finish_non_exhaustive(),
+            ResponseBody::File { len, .. } => f
+                .debug_struct("File")
+                .field("len", len)
+                .finish_non_exhaustive(),
         }
     }
 }
@@ -122,130 +280,523 @@ impl ContentTypeHttpResponse {
 pub(crate) struct HttpResponse {
     // NOTE:: Use this where you think you need to allocate something on heap.
     status_code: u16,
-    // TODO: Need to migrate to `HeadersV2`
-    pub(crate) header: Option<Headers>,
-    pub(crate) body: Option<Vec<u8>>, // TODO: see if we can replace body with some type which doesn't need to
-                                      // allocate memory on heap.
+    /// Overrides the standard reason phrase of `status_code`.
+    reason_phrase: Option<Cow<'static, str>>,
+    pub(crate) header: Option<HeadersV2>,
+    pub(crate) body: Option<ResponseBody>,
+    pub(crate) connection: Option<Connection>,
+    /// Whether the peer understands `Transfer-Encoding: chunked`. When it doesn't (HTTP/1.0),
+    /// streamed bodies of unknown length are delimited by closing the connection instead.
+    pub(crate) chunked_encoding_allowed: bool,
 }
 
 impl Default for HttpResponse {
     fn default() -> Self {
         Self {
             status_code: 200,
+            reason_phrase: None,
             header: None,
             body: None,
+            connection: None,
+            chunked_encoding_allowed: true,
         }
     }
 }
 
 pub(crate) struct HttpResponseBuilder {
     status_code: u16,
-    header: Option<Headers>,
-    body: Option<Vec<u8>>, // TODO: see if we can replace body with some type which doesn't need to
+    reason_phrase: Option<Cow<'static, str>>,
+    header: Option<HeadersV2>,
+    body: Option<ResponseBody>,
 }
 
 impl HttpResponseBuilder {
     pub(crate) fn new(code: u16) -> Self {
         Self {
             status_code: code,
+            reason_phrase: None,
             header: None,
             body: None,
         }
     }
 
+    /// Sends `reason_phrase` instead of the standard one, e.g. for codes that are not
+    /// registered.
     #[allow(dead_code)]
-    pub(crate) fn with_header(mut self, header: Headers) -> Self {
+    pub(crate) fn with_reason_phrase(
+        mut self,
+        reason_phrase: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.reason_phrase = Some(reason_phrase.into());
+        self
+    }
+
+    pub(crate) fn with_header(mut self, header: HeadersV2) -> Self {
         self.header = Some(header);
         self
     }
 
     pub(crate) fn with_body(mut self, body: Vec<u8>) -> Self {
-        self.body = Some(body);
+        self.body = Some(ResponseBody::Full(body));
+        self
+    }
+
+    pub(crate) fn with_stream_body<R>(mut self, reader: R, content_length: Option<u64>) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        self.body = Some(ResponseBody::Stream {
+            reader: Box::new(reader),
+            content_length,
+        });
+        self
+    }
+
+    pub(crate) fn with_file_body(mut self, file: File, len: u64) -> Self {
+        self.body = Some(ResponseBody::File { file, len });
         self
     }
 
     pub(crate) fn build(self) -> HttpResponse {
         HttpResponse {
             status_code: self.status_code,
+            reason_phrase: self.reason_phrase,
             header: self.header,
             body: self.body,
+            connection: None,
+            chunked_encoding_allowed: true,
         }
     }
 }
 
 impl HttpResponse {
-    fn get_http_method_contents_to_write(status_code: u16) -> (&'static str, &'static str) {
-        match status_code {
-            200 => ("200", " OK"),
-            201 => ("201", " Created"),
-            404 => ("404", " Not Found"),
-            500 => ("500", " Internal Server Error"),
-            x => unimplemented!("unhandled status_code: {x}"),
+    pub(crate) fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    pub(crate) fn headers(&self) -> Option<&HeadersV2> {
+        self.header.as_ref()
+    }
+
+    /// The response's headers, created on first use.
+    pub(crate) fn headers_mut(&mut self) -> &mut HeadersV2 {
+        self.header.get_or_insert_with(HeadersV2::new)
+    }
+
+    /// Returns the three digits of the status code and the reason phrase to send with it. A
+    /// custom reason phrase takes precedence over the standard one; unregistered codes without
+    /// one are sent with an empty reason phrase, which RFC 9112 allows.
+    fn get_status_line_contents_to_write(&self) -> anyhow::Result<([u8; 3], &str)> {
+        if !(100..=999).contains(&self.status_code) {
+            anyhow::bail!("invalid status_code: {}", self.status_code);
+        }
+        let digits = [
+            b'0' + (self.status_code / 100) as u8,
+            b'0' + (self.status_code / 10 % 10) as u8,
+            b'0' + (self.status_code % 10) as u8,
+        ];
+        let reason_phrase = match self.reason_phrase.as_deref() {
+            Some(reason_phrase) => {
+                if reason_phrase.contains(['\r', '\n']) {
+                    anyhow::bail!("reason phrase must not contain line breaks");
+                }
+                reason_phrase
+            }
+            None => status_code::reason_phrase(self.status_code).unwrap_or(""),
+        };
+        Ok((digits, reason_phrase))
+    }
+
+    /// Writes `reader` to `writer` using the chunked transfer coding, one chunk per read.
+    fn write_chunked<R, W>(reader: &mut R, writer: &mut W) -> anyhow::Result<()>
+    where
+        R: Read + ?Sized,
+        W: Write,
+    {
+        // NOTE: room for the chunk size line in front of the data and the CRLF after it, so
+        // every chunk goes out with a single write.
+        const CHUNK_SIZE_LINE_CAPACITY: usize = 18;
+        let mut buf = vec![0; CHUNK_SIZE_LINE_CAPACITY + EIGHT_KB_IN_BYTES + 2];
+        loop {
+            let data_end = CHUNK_SIZE_LINE_CAPACITY + EIGHT_KB_IN_BYTES;
+            let bytes_read = match reader.read(&mut buf[CHUNK_SIZE_LINE_CAPACITY..data_end]) {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let size_line = format!("{bytes_read:x}\r\n");
+            let start = CHUNK_SIZE_LINE_CAPACITY - size_line.len();
+            buf[start..CHUNK_SIZE_LINE_CAPACITY].copy_from_slice(size_line.as_bytes());
+            let end = CHUNK_SIZE_LINE_CAPACITY + bytes_read;
+            buf[end..end + 2].copy_from_slice(b"\r\n");
+            writer.write_all(&buf[start..end + 2])?;
         }
+        writer.write_all(b"0\r\n\r\n")?;
+        Ok(())
     }
 
-    fn copy_to_buf(buf: &mut [u8], from: &[u8], buf_offset: usize) -> usize {
-        let bytes_to_copy = from.len();
-        let end = buf_offset + bytes_to_copy;
-        buf[buf_offset..end].copy_from_slice(from);
-        bytes_to_copy
+    fn check_copied(copied: u64, content_length: u64) -> anyhow::Result<()> {
+        if copied != content_length {
+            // NOTE: the body shrank while we were sending it. The advertised `Content-Length`
+            // can no longer be honoured, so the connection has to go.
+            anyhow::bail!("body ended after {copied} of {content_length} bytes");
+        }
+        Ok(())
     }
 
-    pub(crate) fn write<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    pub(crate) fn write<W>(self, writer: &mut W) -> anyhow::Result<()>
     where
         W: Write,
     {
-        let mut buf: [u8; 8196] = [0; 8196]; // NOTE: I think 8 KB should be enough for acting as a
-                                             // buffer for the whole response except for the `body`.
-        let mut bytes_written_to_buf: usize = 0;
-        let b = b"HTTP/1.1 ";
-        buf[0..9].copy_from_slice(b); // 9 bytes.
-        bytes_written_to_buf += 9;
-
-        let (status_code, method_readable_string) =
-            HttpResponse::get_http_method_contents_to_write(self.status_code);
-
-        bytes_written_to_buf +=
-            HttpResponse::copy_to_buf(&mut buf, status_code.as_bytes(), bytes_written_to_buf);
-
-        bytes_written_to_buf += HttpResponse::copy_to_buf(
-            &mut buf,
-            method_readable_string.as_bytes(),
-            bytes_written_to_buf,
-        );
+        let mut head = HeadWriter::new(writer);
+        head.put(b"HTTP/1.1 ")?;
 
-        bytes_written_to_buf += HttpResponse::copy_to_buf(&mut buf, b"\r\n", bytes_written_to_buf);
+        let (status_code, reason_phrase) = self.get_status_line_contents_to_write()?;
+        head.put(&status_code)?;
+        head.put(b" ")?;
+        head.put(reason_phrase.as_bytes())?;
+        head.put(b"\r\n")?;
 
         if let Some(header) = self.header.as_ref() {
             for (k, v) in header.iter() {
-                let k_bytes = k.as_bytes();
-                let v_bytes = v.as_bytes();
-
-                bytes_written_to_buf +=
-                    HttpResponse::copy_to_buf(&mut buf, k_bytes, bytes_written_to_buf);
+                let is_line_break = |b: &u8| *b == b'\r' || *b == b'\n';
+                if k.iter().any(is_line_break) || v.iter().any(is_line_break) {
+                    anyhow::bail!("header fields must not contain line breaks");
+                }
+                head.put_header(k, v)?;
+            }
+        }
+        let chunked = matches!(
+            self.body,
+            Some(ResponseBody::Stream {
+                content_length: None,
+                ..
+            })
+        ) && self.chunked_encoding_allowed;
+        if chunked {
+            head.put_header(b"Transfer-Encoding", b"chunked")?;
+        }
+        if let Some(connection) = self.connection.as_ref() {
+            head.put_header(CONNECTION_HEADER.as_bytes(), connection.header_value())?;
+        }
+        head.put(b"\r\n")?;
+
+        let writer = match self.body {
+            Some(ResponseBody::Full(body)) => return Ok(head.finish_with_body(&body)?),
+            _ => head.finish()?,
+        };
+
+        match self.body {
+            Some(ResponseBody::Stream {
+                mut reader,
+                content_length: None,
+            }) => {
+                if chunked {
+                    HttpResponse::write_chunked(&mut reader, writer)?;
+                } else {
+                    std::io::copy(&mut reader, writer)?;
+                }
+            }
+            Some(ResponseBody::Stream {
+                reader,
+                content_length: Some(content_length),
+            }) => {
+                let copied = std::io::copy(&mut reader.take(content_length), writer)?;
+                HttpResponse::check_copied(copied, content_length)?;
+            }
+            Some(ResponseBody::File { file, len }) => {
+                let copied = std::io::copy(&mut file.take(len), writer)?;
+                HttpResponse::check_copied(copied, len)?;
+            }
+            Some(ResponseBody::Full(_)) | None => {}
+        }
+        Ok(())
+    }
+}
 
-                bytes_written_to_buf +=
-                    HttpResponse::copy_to_buf(&mut buf, b": ", bytes_written_to_buf);
+/// Collects the status line and headers of a response in a fixed size stack buffer and hands
+/// them to the writer whenever it fills up. Heads that fit in the buffer, which is nearly all
+/// of them, go out without any heap allocation in a single write; bigger ones are written out
+/// in pieces.
+struct HeadWriter<'a, W>
+where
+    W: Write,
+{
+    writer: &'a mut W,
+    buf: [u8; EIGHT_KB_IN_BYTES],
+    len: usize,
+}
 
-                bytes_written_to_buf +=
-                    HttpResponse::copy_to_buf(&mut buf, v_bytes, bytes_written_to_buf);
+impl<'a, W> HeadWriter<'a, W>
+where
+    W: Write,
+{
+    fn new(writer: &'a mut W) -> Self {
+        Self {
+            writer,
+            buf: [0; EIGHT_KB_IN_BYTES],
+            len: 0,
+        }
+    }
 
-                bytes_written_to_buf +=
-                    HttpResponse::copy_to_buf(&mut buf, b"\r\n", bytes_written_to_buf);
+    fn put(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if self.len + bytes.len() > self.buf.len() {
+            self.flush()?;
+            if bytes.len() > self.buf.len() {
+                return self.writer.write_all(bytes);
             }
         }
-        bytes_written_to_buf += HttpResponse::copy_to_buf(&mut buf, b"\r\n", bytes_written_to_buf);
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
 
-        writer.write_all(&buf[0..bytes_written_to_buf])?;
+    fn put_header(&mut self, key: &[u8], val: &[u8]) -> std::io::Result<()> {
+        self.put(key)?;
+        self.put(b": ")?;
+        self.put(val)?;
+        self.put(b"\r\n")
+    }
 
-        if self.body.is_some() {
-            match &self.body {
-                Some(body) => {
-                    writer.write_all(body)?;
-                }
-                None => {}
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.write_all(&self.buf[..self.len])?;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Writes out whatever is still buffered and gives the writer back for the body.
+    fn finish(mut self) -> std::io::Result<&'a mut W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Writes out whatever is still buffered together with `body`, with vectored writes so a
+    /// small response still goes out in a single syscall.
+    fn finish_with_body(self, body: &[u8]) -> std::io::Result<()> {
+        let mut head = &self.buf[..self.len];
+        let mut body = body;
+        while !head.is_empty() {
+            let written = match self
+                .writer
+                .write_vectored(&[IoSlice::new(head), IoSlice::new(body)])
+            {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(written) => written,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if written < head.len() {
+                head = &head[written..];
+            } else {
+                body = &body[written - head.len()..];
+                head = &[];
             }
         }
+        self.writer.write_all(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn written(response: HttpResponse) -> anyhow::Result<String> {
+        let mut out = Vec::new();
+        response.write(&mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    fn fields(headers: &HeadersV2) -> Vec<(&str, &str)> {
+        headers
+            .iter()
+            .map(|(k, v)| {
+                (
+                    std::str::from_utf8(k).unwrap_or_default(),
+                    std::str::from_utf8(v).unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn looks_up_names_case_insensitively() {
+        let mut headers = HeadersV2::new();
+        assert!(headers.is_empty());
+        headers.append("Content-Type", "text/plain");
+        assert!(!headers.is_empty());
+        assert!(headers.contains(b"content-type"));
+        assert_eq!(
+            headers.get(b"CONTENT-TYPE"),
+            Some(&Bytes::from("text/plain"))
+        );
+        assert_eq!(headers.get(b"Content-Length"), None);
+        assert!(!headers.contains(b"Content-Length"));
+    }
+
+    #[test]
+    fn get_returns_the_first_value() {
+        let mut headers = HeadersV2::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.get(b"Set-Cookie"), Some(&Bytes::from("a=1")));
+        assert_eq!(
+            headers.get_all(b"SET-COOKIE").collect::<Vec<_>>(),
+            vec!["a=1", "b=2"]
+        );
+        assert_eq!(headers.get_all(b"Vary").count(), 0);
+    }
+
+    #[test]
+    fn insert_replaces_in_place() {
+        let mut headers = HeadersV2::new();
+        headers.append("Vary", "Accept");
+        headers.append("ETag", "\"1\"");
+        headers.append("vary", "Accept-Encoding");
+        headers.append("Server", "test");
+        headers.insert("VARY", "*");
+        assert_eq!(
+            fields(&headers),
+            vec![("Vary", "*"), ("ETag", "\"1\""), ("Server", "test")]
+        );
+
+        headers.insert("Content-Length", "0");
+        assert_eq!(
+            fields(&headers),
+            vec![
+                ("Vary", "*"),
+                ("ETag", "\"1\""),
+                ("Server", "test"),
+                ("Content-Length", "0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_list_spans_field_lines() {
+        let mut headers = HeadersV2::new();
+        headers.append("Accept-Encoding", "gzip, , deflate");
+        headers.append("Accept", "text/html");
+        headers.append("accept-encoding", ",br ,");
+        headers.append("Accept-Encoding", "");
+        assert_eq!(
+            headers.get_list(b"Accept-Encoding").collect::<Vec<_>>(),
+            vec!["gzip", "deflate", "br"]
+        );
+        assert_eq!(headers.get_list(b"TE").count(), 0);
+    }
+
+    #[test]
+    fn writes_response_byte_for_byte() -> anyhow::Result<()> {
+        let mut header = HeadersV2::new();
+        header.append("Content-Type", "text/plain");
+        header.append("Set-Cookie", "a=1; Path=/");
+        header.append("X-Custom", "x");
+        header.append("Set-Cookie", "b=2");
+        header.append("Content-Length", "5");
+        let mut response = HttpResponseBuilder::new(200)
+            .with_header(header)
+            .with_body(b"hello".to_vec())
+            .build();
+        response.connection = Some(Connection::KeepAlive);
+        assert_eq!(
+            written(response)?,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain\r\n\
+             Set-Cookie: a=1; Path=/\r\n\
+             X-Custom: x\r\n\
+             Set-Cookie: b=2\r\n\
+             Content-Length: 5\r\n\
+             Connection: keep-alive\r\n\
+             \r\n\
+             hello"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_status_line() -> anyhow::Result<()> {
+        assert_eq!(
+            written(HttpResponseBuilder::new(404).build())?,
+            "HTTP/1.1 404 Not Found\r\n\r\n"
+        );
+        assert_eq!(
+            written(
+                HttpResponseBuilder::new(299)
+                    .with_reason_phrase("Custom")
+                    .build()
+            )?,
+            "HTTP/1.1 299 Custom\r\n\r\n"
+        );
+        assert_eq!(
+            written(HttpResponseBuilder::new(299).build())?,
+            "HTTP/1.1 299 \r\n\r\n"
+        );
+        assert!(written(HttpResponseBuilder::new(1000).build()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_stream_of_unknown_length_chunked() -> anyhow::Result<()> {
+        let mut response = HttpResponseBuilder::new(200)
+            .with_stream_body(Cursor::new(b"hello".to_vec()), None)
+            .build();
+        response.connection = Some(Connection::Close);
+        assert_eq!(
+            written(response)?,
+            "HTTP/1.1 200 OK\r\n\
+             Transfer-Encoding: chunked\r\n\
+             Connection: close\r\n\
+             \r\n\
+             5\r\nhello\r\n0\r\n\r\n"
+        );
+
+        let mut response = HttpResponseBuilder::new(200)
+            .with_stream_body(Cursor::new(b"hello".to_vec()), None)
+            .build();
+        response.chunked_encoding_allowed = false;
+        assert_eq!(written(response)?, "HTTP/1.1 200 OK\r\n\r\nhello");
         Ok(())
     }
+
+    #[test]
+    fn writes_stream_of_known_length() -> anyhow::Result<()> {
+        let response = HttpResponseBuilder::new(200)
+            .with_stream_body(Cursor::new(b"hello, world".to_vec()), Some(5))
+            .build();
+        assert_eq!(written(response)?, "HTTP/1.1 200 OK\r\n\r\nhello");
+
+        // NOTE: a body shorter than announced would leave the client waiting for the rest.
+        let response = HttpResponseBuilder::new(200)
+            .with_stream_body(Cursor::new(b"hi".to_vec()), Some(5))
+            .build();
+        assert!(written(response).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn writes_heads_larger_than_the_buffer() -> anyhow::Result<()> {
+        let value = "v".repeat(EIGHT_KB_IN_BYTES);
+        let mut header = HeadersV2::new();
+        header.append("X-Big", value.clone());
+        header.append("X-After", "1");
+        let response = HttpResponseBuilder::new(204).with_header(header).build();
+        assert_eq!(
+            written(response)?,
+            format!("HTTP/1.1 204 No Content\r\nX-Big: {value}\r\nX-After: 1\r\n\r\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_line_breaks_in_fields() {
+        for (name, value) in [("X-Bad", "a\r\nInjected: 1"), ("X-Bad\n", "a")] {
+            let mut header = HeadersV2::new();
+            header.append(name.to_string(), value.to_string());
+            let response = HttpResponseBuilder::new(200).with_header(header).build();
+            assert!(written(response).is_err(), "{name:?}: {value:?}");
+        }
+    }
 }
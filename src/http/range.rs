@@ -0,0 +1,463 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Seek, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// More ranges than this in one request are served as the full representation instead. Lots of
+/// tiny ranges cost far more to serve than they save, RFC 9110 section 14.2.
+const MAX_RANGES: usize = 32;
+
+/// Inclusive range of byte offsets, as in `Content-Range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+}
+
+impl ByteRange {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub(crate) fn content_range(&self, complete_length: u64) -> String {
+        format!("bytes {}-{}/{complete_length}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeRequest {
+    /// No usable `Range`, the whole representation is served.
+    Full,
+    /// The ranges to serve, in ascending order and none overlapping or adjacent to another.
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlaps the representation, answered with `416`.
+    Unsatisfiable,
+}
+
+/// Interprets the value of a `Range` header against a representation of `len` bytes. Headers
+/// we can't make sense of are ignored, as RFC 9110 section 14.2 allows.
+pub(crate) fn parse_range(value: &[u8], len: u64) -> RangeRequest {
+    let Ok(value) = std::str::from_utf8(value) else {
+        return RangeRequest::Full;
+    };
+    let Some((unit, specs)) = value.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    let mut spec_count: usize = 0;
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        spec_count += 1;
+        if spec_count > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: len.saturating_sub(suffix),
+                    end: len - 1,
+                }
+            }
+            (first, last) => {
+                let Ok(start) = first.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                let end = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return RangeRequest::Full,
+                    },
+                };
+                if start >= len {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.min(len - 1),
+                }
+            }
+        };
+        ranges.push(range);
+    }
+
+    if spec_count == 0 {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(coalesce(ranges))
+    }
+}
+
+/// Sorts `ranges` and merges the ones that overlap or touch, so no byte is sent twice however
+/// the client asked for them. RFC 9110 section 14.2 allows this, and it takes the teeth out of
+/// requests like `bytes=0-,0-,0-` that would otherwise have the same bytes sent over and over.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+/// Parses the `Content-Range: bytes first-last/complete-length` of a partial write. The
+/// complete length may be `*`, when given it has to be past the range.
+pub(crate) fn parse_content_range(value: &[u8]) -> Option<ByteRange> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (unit, range) = value.split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, complete_length) = range.trim().split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let range = ByteRange {
+        start: first.parse().ok()?,
+        end: last.parse().ok()?,
+    };
+    // NOTE: a range ending at `u64::MAX` is longer than any length we can represent.
+    if range.end < range.start || range.end == u64::MAX {
+        return None;
+    }
+    match complete_length {
+        "*" => Some(range),
+        complete_length => {
+            let complete_length = complete_length.parse::<u64>().ok()?;
+            (range.end < complete_length).then_some(range)
+        }
+    }
+}
+
+/// A boundary for `multipart/byteranges` that is unique per response.
+pub(crate) fn multipart_boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_nanos() as u64)
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("byteranges-{nanos:016x}{count:08x}")
+}
+
+enum Part {
+    Bytes(io::Cursor<Vec<u8>>),
+    Range {
+        start: u64,
+        remaining: u64,
+        positioned: bool,
+    },
+}
+
+/// Streams a `multipart/byteranges` body out of `inner`, seeking to each range as it is
+/// reached. RFC 9110 section 14.6.
+pub(crate) struct MultipartRanges<R> {
+    inner: R,
+    parts: VecDeque<Part>,
+    len: u64,
+}
+
+impl<R> MultipartRanges<R>
+where
+    R: Read + Seek,
+{
+    pub(crate) fn new(
+        inner: R,
+        ranges: &[ByteRange],
+        complete_length: u64,
+        content_type: &str,
+        boundary: &str,
+    ) -> Self {
+        let mut parts = VecDeque::new();
+        let mut len = 0;
+        let mut push_bytes = |parts: &mut VecDeque<Part>, bytes: String| {
+            len += bytes.len() as u64;
+            parts.push_back(Part::Bytes(io::Cursor::new(bytes.into_bytes())));
+        };
+        for range in ranges {
+            push_bytes(
+                &mut parts,
+                format!(
+                    "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                    range.content_range(complete_length)
+                ),
+            );
+            parts.push_back(Part::Range {
+                start: range.start,
+                remaining: range.len(),
+                positioned: false,
+            });
+        }
+        push_bytes(&mut parts, format!("\r\n--{boundary}--\r\n"));
+        len += ranges.iter().map(ByteRange::len).sum::<u64>();
+        Self { inner, parts, len }
+    }
+
+    /// Length of the whole multipart body.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<R> Read for MultipartRanges<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(cursor) => cursor.read(buf)?,
+                Part::Range {
+                    start,
+                    remaining,
+                    positioned,
+                } => {
+                    if !*positioned {
+                        self.inner.seek(SeekFrom::Start(*start))?;
+                        *positioned = true;
+                    }
+                    let max = buf
+                        .len()
+                        .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    let read = self.inner.read(&mut buf[..max])?;
+                    if read == 0 && *remaining > 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::Itertools;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            parse_content_range(b"bytes 0-9/*"),
+            Some(ByteRange { start: 0, end: 9 })
+        );
+        assert_eq!(
+            parse_content_range(b"bytes 5-9/10"),
+            Some(ByteRange { start: 5, end: 9 })
+        );
+        assert_eq!(parse_content_range(b"bytes 5-10/10"), None);
+        assert_eq!(parse_content_range(b"bytes 9-5/*"), None);
+        assert_eq!(parse_content_range(b"items 0-9/*"), None);
+        assert_eq!(parse_content_range(b"bytes 0-9"), None);
+    }
+
+    #[test]
+    fn rejects_content_range_up_to_u64_max() {
+        assert_eq!(parse_content_range(b"bytes 0-18446744073709551615/*"), None);
+        let range = parse_content_range(b"bytes 0-18446744073709551614/*");
+        assert_eq!(range.map(|range| range.len()), Some(u64::MAX));
+    }
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            parse_range(b"bytes=0-4", 10),
+            RangeRequest::Partial(vec![range(0, 4)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=5-100", 10),
+            RangeRequest::Partial(vec![range(5, 9)])
+        );
+        assert_eq!(
+            parse_range(b"Bytes = 8-, 0-0", 10),
+            RangeRequest::Partial(vec![range(0, 0), range(8, 9)])
+        );
+    }
+
+    #[test]
+    fn coalesces_overlapping_and_adjacent_ranges() {
+        assert_eq!(
+            parse_range(b"bytes=0-,0-,0-", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=0-4,5-9", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=3-7,0-5,9-9", 10),
+            RangeRequest::Partial(vec![range(0, 7), range(9, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=2-3,0-8,-4", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=7-8,1-2,4-4", 10),
+            RangeRequest::Partial(vec![range(1, 2), range(4, 4), range(7, 8)])
+        );
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(
+            parse_range(b"bytes=-3", 10),
+            RangeRequest::Partial(vec![range(7, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=-30", 10),
+            RangeRequest::Partial(vec![range(0, 9)])
+        );
+        assert_eq!(parse_range(b"bytes=-0", 10), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn parses_open_ended_ranges() {
+        assert_eq!(
+            parse_range(b"bytes=3-", 10),
+            RangeRequest::Partial(vec![range(3, 9)])
+        );
+        assert_eq!(
+            parse_range(b"bytes=9-", 10),
+            RangeRequest::Partial(vec![range(9, 9)])
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(parse_range(b"bytes=10-", 10), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(b"bytes=10-20", 10), RangeRequest::Unsatisfiable);
+        // NOTE: one satisfiable range is enough for the others to be ignored.
+        assert_eq!(
+            parse_range(b"bytes=10-20, 2-3", 10),
+            RangeRequest::Partial(vec![range(2, 3)])
+        );
+    }
+
+    #[test]
+    fn empty_representations_have_no_satisfiable_ranges() {
+        for value in [&b"bytes=0-"[..], b"bytes=0-0", b"bytes=-5"] {
+            assert_eq!(parse_range(value, 0), RangeRequest::Unsatisfiable);
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_are_served_in_full() {
+        let specs = (0..=MAX_RANGES)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .join(",");
+        assert_eq!(
+            parse_range(format!("bytes={specs}").as_bytes(), 100),
+            RangeRequest::Full
+        );
+        let specs = (0..MAX_RANGES).map(|i| format!("{0}-{0}", i * 2)).join(",");
+        assert!(matches!(
+            parse_range(format!("bytes={specs}").as_bytes(), 100),
+            RangeRequest::Partial(ranges) if ranges.len() == MAX_RANGES
+        ));
+    }
+
+    #[test]
+    fn ignores_malformed_ranges() {
+        for value in [
+            &b"bytes=5-4"[..],
+            b"bytes=a-b",
+            b"bytes=0-4,x",
+            b"bytes=-",
+            b"bytes=1",
+            b"bytes=--1",
+            b"items=0-4",
+            b"bytes 0-4",
+            b"bytes=",
+            b"bytes=0-4\xff",
+        ] {
+            assert_eq!(parse_range(value, 10), RangeRequest::Full, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn multipart_len_matches_body() -> io::Result<()> {
+        let content = (0..=255).collect::<Vec<u8>>();
+        let ranges = [range(0, 0), range(10, 19), range(250, 255), range(5, 14)];
+        let mut body = MultipartRanges::new(
+            io::Cursor::new(&content),
+            &ranges,
+            content.len() as u64,
+            "application/octet-stream",
+            "boundary",
+        );
+        let expected_len = body.len();
+        let mut produced = Vec::new();
+        body.read_to_end(&mut produced)?;
+        assert_eq!(produced.len() as u64, expected_len);
+
+        let produced = String::from_utf8_lossy(&produced);
+        assert!(produced.starts_with(
+            "\r\n--boundary\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-0/256\r\n\r\n"
+        ));
+        assert!(produced.ends_with("\r\n--boundary--\r\n"));
+        assert_eq!(produced.matches("Content-Range: ").count(), ranges.len());
+        Ok(())
+    }
+
+    #[test]
+    fn multipart_reads_ranges_in_small_pieces() -> io::Result<()> {
+        let content = b"0123456789".to_vec();
+        let mut body = MultipartRanges::new(
+            io::Cursor::new(&content),
+            &[range(7, 9), range(1, 2)],
+            10,
+            "text/plain",
+            "b",
+        );
+        let mut produced = Vec::new();
+        let mut piece = [0; 3];
+        loop {
+            match body.read(&mut piece)? {
+                0 => break,
+                read => produced.extend_from_slice(&piece[..read]),
+            }
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&produced),
+            "\r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n789\
+             \r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 1-2/10\r\n\r\n12\
+             \r\n--b--\r\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use bytes::BytesMut;
+use std::{
+    cell::RefCell,
+    io::{self, Read},
+    net::TcpStream,
+};
+
+use super::{
+    request_parser::{BodyDecoder, ParseStatus},
+    HeadersV2, HttpError, EIGHT_KB_IN_BYTES,
+};
+
+/// The read side of a connection: the socket, and whatever was read from it that no request
+/// has consumed yet. It is lent to each request in turn so its body can be read from it.
+pub(crate) struct RequestSource {
+    stream: TcpStream,
+    pub(crate) buf: BytesMut,
+}
+
+impl RequestSource {
+    pub(crate) fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::with_capacity(EIGHT_KB_IN_BYTES),
+        }
+    }
+
+    /// Appends up to 8 KB from the socket to `buf`, returning how many bytes that was. `0`
+    /// means the client closed its side of the connection.
+    pub(crate) fn fill(&mut self) -> io::Result<usize> {
+        let filled = self.buf.len();
+        self.buf.resize(filled + EIGHT_KB_IN_BYTES, 0);
+        let bytes_read = self.stream.read(&mut self.buf[filled..]);
+        self.buf
+            .truncate(filled + *bytes_read.as_ref().unwrap_or(&0));
+        bytes_read
+    }
+}
+
+struct BodyReader {
+    /// `None` for requests without a body.
+    decoder: Option<BodyDecoder>,
+    source: Option<RequestSource>,
+    /// Set once reading failed, after that the connection is out of sync with the client.
+    failed: bool,
+}
+
+/// Body of a request, read incrementally from the connection as the handler asks for it.
+/// `&RequestBody` implements `Read`, so a handler that only gets `&HttpRequestV2` can still
+/// stream the body somewhere with `std::io::copy(&mut &req.body, ...)`.
+///
+/// Reading fails with an `io::Error` wrapping the `HttpError`, see `HttpError::from_io`, when
+/// the body is malformed or exceeds the maximum body size.
+pub(crate) struct RequestBody {
+    // NOTE: handlers only get a shared reference to the request, and a request never leaves
+    // the worker that reads it, so a `RefCell` is all the mutability we need.
+    reader: RefCell<BodyReader>,
+}
+
+impl RequestBody {
+    pub(crate) fn new(decoder: Option<BodyDecoder>) -> Self {
+        Self {
+            reader: RefCell::new(BodyReader {
+                decoder,
+                source: None,
+                failed: false,
+            }),
+        }
+    }
+
+    /// Lends the connection to the body, until `finish` hands it back.
+    pub(crate) fn attach(&self, source: RequestSource) {
+        self.reader.borrow_mut().source = Some(source);
+    }
+
+    /// Whether the request was sent with a body at all. An empty `Content-Length: 0` body
+    /// counts as none.
+    pub(crate) fn is_present(&self) -> bool {
+        self.reader.borrow().decoder.is_some()
+    }
+
+    /// Whether reading the body failed, leaving the connection out of sync with the client.
+    pub(crate) fn has_failed(&self) -> bool {
+        self.reader.borrow().failed
+    }
+
+    /// Reads the rest of the body into memory.
+    pub(crate) fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        (&mut &*self).read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// Fields sent after a chunked body, complete once the body has been read to its end.
+    #[allow(dead_code)]
+    pub(crate) fn trailers(&self) -> Option<HeadersV2> {
+        let reader = self.reader.borrow();
+        let trailers = reader.decoder.as_ref()?.trailers();
+        (!trailers.is_empty()).then(|| trailers.clone())
+    }
+
+    /// Skips whatever the handler left unread of the body and hands the connection back for
+    /// the next request. Fails when the body couldn't be read to its end, after which the
+    /// connection can't be used for further requests.
+    pub(crate) fn finish(&self) -> Result<RequestSource, HttpError> {
+        let mut scratch = [0; EIGHT_KB_IN_BYTES];
+        loop {
+            match (&mut &*self).read(&mut scratch) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(err) => return Err(HttpError::from_io(err)),
+            }
+        }
+        let mut reader = self.reader.borrow_mut();
+        if reader.failed {
+            return Err(HttpError::ConnectionClosed);
+        }
+        reader.source.take().ok_or(HttpError::ConnectionClosed)
+    }
+}
+
+impl Read for &RequestBody {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.borrow_mut();
+        let BodyReader {
+            decoder,
+            source,
+            failed,
+        } = &mut *reader;
+        if *failed {
+            return Err(io::Error::other(HttpError::ConnectionClosed));
+        }
+        let Some(decoder) = decoder.as_mut() else {
+            return Ok(0);
+        };
+        let Some(source) = source.as_mut() else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        loop {
+            let decoded = match decoder.decode(&mut source.buf, out) {
+                Ok(ParseStatus::Complete(decoded)) => return Ok(decoded),
+                // NOTE: wrapped like the decoding errors, so a handler copying the body
+                // somewhere can tell failures on our side from failures on the client's.
+                Ok(ParseStatus::Incomplete) => source
+                    .fill()
+                    .map_err(|err| io::Error::other(HttpError::IoErr(err))),
+                Err(err) => Err(io::Error::other(err)),
+            };
+            match decoded {
+                Ok(0) => {
+                    *failed = true;
+                    return Err(io::Error::other(HttpError::ConnectionClosed));
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    *failed = true;
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,783 @@
+use crate::http::HttpError;
+use bytes::{Buf, Bytes, BytesMut};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take_until},
+    character::complete::space0,
+    combinator::value,
+    sequence::terminated,
+    IResult,
+};
+
+use super::{
+    http_request::{HttpRequestV2, HttpVersion, Method},
+    request_body::RequestBody,
+    HeadersV2, EIGHT_KB_IN_BYTES,
+};
+
+/// Upper bound on the request line. Anything longer is answered with `414 URI Too Long`, as the
+/// target is the only part of it that can legitimately grow.
+pub(crate) const MAX_REQUEST_LINE_SIZE_IN_BYTES: usize = EIGHT_KB_IN_BYTES;
+/// Upper bound on the bytes buffered for the header section (and for trailers). Without it a
+/// client that never sends the terminating empty line would make us buffer forever.
+pub(crate) const MAX_HEADER_SECTION_SIZE_IN_BYTES: usize = 8 * EIGHT_KB_IN_BYTES;
+/// Upper bound on a single chunk-size line, including any chunk extensions.
+const MAX_CHUNK_SIZE_LINE_IN_BYTES: usize = 1024;
+
+fn parse_method(input: &[u8]) -> IResult<&[u8], Method> {
+    terminated(
+        alt((
+            value(Method::Get, tag_no_case(b"get")),
+            value(Method::Head, tag_no_case(b"head")),
+            value(Method::Post, tag_no_case(b"post")),
+            value(Method::Put, tag_no_case(b"put")),
+            value(Method::Delete, tag_no_case(b"delete")),
+            value(Method::Patch, tag_no_case(b"patch")),
+        )),
+        tag(" "),
+    )(input)
+}
+
+fn parse_version(input: &[u8]) -> IResult<&[u8], HttpVersion> {
+    alt((
+        value(HttpVersion::Http11, tag(b"HTTP/1.1")),
+        value(HttpVersion::Http10, tag(b"HTTP/1.0")),
+    ))(input)
+}
+
+/// `tchar` from RFC 9110 section 5.6.2, the characters allowed in methods and field names.
+fn is_token_char(byte: &u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(byte)
+}
+
+fn skip_whitespaces0(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    space0(input)
+}
+
+fn capture_all_till_and_including_space(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    terminated(take_until(" "), tag(" "))(input)
+}
+
+fn capture_all_till_and_including_crlf(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    terminated(take_until("\r\n"), tag("\r\n"))(input)
+}
+
+fn capture_all_till_and_including_termination_character<'a>(
+    input: &'a [u8],
+    termination_bytes: &'a [u8],
+) -> IResult<&'a [u8], &'a [u8]> {
+    terminated(take_until(termination_bytes), tag(termination_bytes))(input)
+}
+
+/// Outcome of feeding bytes to the `RequestParser`. Malformed input is reported through the
+/// `Err` side of the `Result` instead.
+pub(crate) enum ParseStatus<T> {
+    Complete(T),
+    /// Everything received so far is valid but the request is not finished yet.
+    Incomplete,
+}
+
+enum ParserState {
+    RequestLine,
+    Headers,
+}
+
+enum BodyState {
+    Body {
+        remaining: usize,
+    },
+    ChunkSize,
+    ChunkData {
+        remaining: usize,
+    },
+    /// The CRLF that terminates the data of every chunk.
+    ChunkDataEnd,
+    Trailers,
+    Done,
+}
+
+/// How the end of the request body is determined, see RFC 9112 section 6.3.
+enum BodyFraming {
+    NoBody,
+    ContentLength(usize),
+    Chunked,
+}
+
+/// Resumable parser for the head of a request. Bytes are fed to it as they arrive from the
+/// socket, and it consumes from the front of the buffer whatever it could make sense of, so it
+/// can be called again with the same buffer once more bytes have been appended to it. The body
+/// is left in the buffer for the `BodyDecoder` of the request.
+pub(crate) struct RequestParser {
+    state: ParserState,
+    max_body_size: usize,
+    header_bytes_parsed: usize,
+    method: Method,
+    path: Bytes,
+    version: HttpVersion,
+    headers: HeadersV2,
+}
+
+impl RequestParser {
+    pub(crate) fn new(max_body_size: usize) -> Self {
+        Self {
+            state: ParserState::RequestLine,
+            max_body_size,
+            header_bytes_parsed: 0,
+            method: Method::Get,
+            path: Bytes::new(),
+            version: HttpVersion::Http11,
+            headers: HeadersV2::new(),
+        }
+    }
+
+    pub(crate) fn parse(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<ParseStatus<HttpRequestV2>, HttpError> {
+        loop {
+            match self.state {
+                ParserState::RequestLine => {
+                    let Some(line) = self.next_head_line(buf)? else {
+                        return Ok(ParseStatus::Incomplete);
+                    };
+                    self.parse_request_line(line)?;
+                    self.state = ParserState::Headers;
+                }
+                ParserState::Headers => {
+                    let Some(line) = self.next_head_line(buf)? else {
+                        return Ok(ParseStatus::Incomplete);
+                    };
+                    if !line.is_empty() {
+                        let (key, val) = RequestParser::parse_header_line(line)?;
+                        self.headers.append(key, val);
+                        continue;
+                    }
+                    let decoder = match self.body_framing()? {
+                        BodyFraming::NoBody | BodyFraming::ContentLength(0) => None,
+                        BodyFraming::ContentLength(content_length) => {
+                            if content_length > self.max_body_size {
+                                return Err(HttpError::PayloadTooLarge);
+                            }
+                            Some(BodyDecoder::new(
+                                BodyState::Body {
+                                    remaining: content_length,
+                                },
+                                self.max_body_size,
+                            ))
+                        }
+                        BodyFraming::Chunked => {
+                            Some(BodyDecoder::new(BodyState::ChunkSize, self.max_body_size))
+                        }
+                    };
+                    return Ok(ParseStatus::Complete(self.finish(decoder)));
+                }
+            }
+        }
+    }
+
+    /// Splits the next line of the head off `buf`, see `split_line`.
+    fn next_head_line(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, HttpError> {
+        let line = match self.state {
+            ParserState::RequestLine => {
+                split_line(buf, MAX_REQUEST_LINE_SIZE_IN_BYTES, HttpError::UriTooLong)?
+            }
+            ParserState::Headers => split_line(
+                buf,
+                MAX_HEADER_SECTION_SIZE_IN_BYTES.saturating_sub(self.header_bytes_parsed),
+                HttpError::HeaderSectionTooLarge,
+            )?,
+        };
+        if let (ParserState::Headers, Some(line)) = (&self.state, line.as_ref()) {
+            self.header_bytes_parsed += line.len() + 2;
+        }
+        Ok(line)
+    }
+
+    fn parse_request_line(&mut self, line: Bytes) -> Result<(), HttpError> {
+        let (rest, method) = parse_method(&line).map_err(|_| {
+            // NOTE: a well formed token we just don't know is a method we don't implement,
+            // anything else is garbage.
+            match capture_all_till_and_including_space(&line) {
+                Ok((_, token)) if !token.is_empty() && token.iter().all(is_token_char) => {
+                    HttpError::UnknownMethod
+                }
+                _ => HttpError::RequestParsingError("malformed request line"),
+            }
+        })?;
+        let (rest, _) = skip_whitespaces0(rest)
+            .map_err(|_| HttpError::RequestParsingError("error while skipping spaces"))?;
+        let (rest, path) = capture_all_till_and_including_space(rest)
+            .map_err(|_| HttpError::RequestParsingError("error while parsing path"))?;
+
+        // Validate that the path is valid UTF-8.
+        if let Err(err) = std::str::from_utf8(path) {
+            return Err(HttpError::Utf8Error(err));
+        }
+
+        let (rest, _) = skip_whitespaces0(rest)
+            .map_err(|_| HttpError::RequestParsingError("error while skipping spaces"))?;
+        let version = match parse_version(rest) {
+            Ok((&[], version)) => version,
+            _ => match rest {
+                [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+                    if major.is_ascii_digit() && minor.is_ascii_digit() =>
+                {
+                    return Err(HttpError::UnsupportedHttpVersion)
+                }
+                _ => return Err(HttpError::HttpVersionParseError),
+            },
+        };
+
+        self.method = method;
+        self.path = line.slice_ref(path);
+        self.version = version;
+        Ok(())
+    }
+
+    fn parse_header_line(line: Bytes) -> Result<(Bytes, Bytes), HttpError> {
+        let (header_rest, key_bytes) =
+            capture_all_till_and_including_termination_character(&line, b":")
+                .map_err(|_| HttpError::RequestParsingError("error while capturing header key"))?;
+        if key_bytes.is_empty() || !key_bytes.iter().all(is_token_char) {
+            return Err(HttpError::RequestParsingError("invalid header key."));
+        }
+
+        let (header_rest, _) = skip_whitespaces0(header_rest).map_err(|_| {
+            HttpError::RequestParsingError("error while skipping skip_whitespaces0")
+        })?;
+        let value_len = header_rest
+            .iter()
+            .rposition(|b| *b != b' ' && *b != b'\t')
+            .map_or(0, |pos| pos + 1);
+        let value_bytes = &header_rest[..value_len];
+
+        if std::str::from_utf8(key_bytes).is_err() {
+            return Err(HttpError::RequestParsingError("invalid header key."));
+        }
+        if std::str::from_utf8(value_bytes).is_err() {
+            return Err(HttpError::RequestParsingError("invalid header val."));
+        }
+
+        Ok((line.slice_ref(key_bytes), line.slice_ref(value_bytes)))
+    }
+
+    fn body_framing(&self) -> Result<BodyFraming, HttpError> {
+        let has_transfer_encoding = self.headers.contains(b"Transfer-Encoding");
+        let has_content_length = self.headers.contains(b"Content-Length");
+
+        match (has_transfer_encoding, has_content_length) {
+            (false, false) => Ok(BodyFraming::NoBody),
+            // NOTE: a request carrying both is the classic request smuggling vector, where a
+            // proxy in front of us might frame the body differently than we do.
+            (true, true) => Err(HttpError::RequestParsingError(
+                "both Content-Length and Transfer-Encoding present",
+            )),
+            (false, true) => {
+                // NOTE: repeated `Content-Length` fields are only acceptable when they all
+                // agree, RFC 9112 section 6.3.
+                let mut content_lengths = self.headers.get_list(b"Content-Length").map(|val| {
+                    // NOTE: `Content-Length = 1*DIGIT`, RFC 9112 section 6.2. `parse` would
+                    // take a leading `+` too, which a proxy in front of us may not.
+                    let is_digits = !val.is_empty() && val.bytes().all(|b| b.is_ascii_digit());
+                    is_digits.then(|| val.parse::<usize>().ok()).flatten()
+                });
+                let first = content_lengths.next().flatten();
+                match first {
+                    Some(len) if content_lengths.all(|other| other == Some(len)) => {
+                        Ok(BodyFraming::ContentLength(len))
+                    }
+                    _ => Err(HttpError::InvalidContentLengthInRequest),
+                }
+            }
+            (true, false) => {
+                // `chunked` is the only transfer coding we know how to undo, so it has to be
+                // the only one applied.
+                let mut codings = self.headers.get_list(b"Transfer-Encoding");
+                let is_chunked = matches!(
+                    (codings.next(), codings.next()),
+                    (Some(coding), None) if coding.eq_ignore_ascii_case("chunked")
+                );
+                if is_chunked {
+                    Ok(BodyFraming::Chunked)
+                } else {
+                    Err(HttpError::UnsupportedTransferEncoding)
+                }
+            }
+        }
+    }
+
+    /// Hands out the parsed request and resets the parser so it can be reused for the next
+    /// request on the same connection.
+    fn finish(&mut self, decoder: Option<BodyDecoder>) -> HttpRequestV2 {
+        let parser = std::mem::replace(self, RequestParser::new(self.max_body_size));
+        let headers = if !parser.headers.is_empty() {
+            Some(parser.headers)
+        } else {
+            None
+        };
+        HttpRequestV2 {
+            method: parser.method,
+            path: parser.path,
+            version: parser.version,
+            headers,
+            body: RequestBody::new(decoder),
+        }
+    }
+}
+
+/// Splits the next CRLF terminated line off `buf`, without the CRLF. Returns `None` when the
+/// line has not fully arrived yet, and `too_large` once it is longer than `limit` bytes.
+fn split_line(
+    buf: &mut BytesMut,
+    limit: usize,
+    too_large: HttpError,
+) -> Result<Option<Bytes>, HttpError> {
+    let line_len = match capture_all_till_and_including_crlf(buf) {
+        Ok((rest, _)) => buf.len() - rest.len(),
+        Err(_) if buf.len() > limit => return Err(too_large),
+        Err(_) => return Ok(None),
+    };
+    if line_len > limit {
+        return Err(too_large);
+    }
+    let mut line = buf.split_to(line_len).freeze();
+    line.truncate(line_len - 2);
+    Ok(Some(line))
+}
+
+/// Undoes the framing of a request body, handing out the body bytes as they are decoded so
+/// the body never has to be held in memory as a whole. Like the `RequestParser` it consumes
+/// what it could make sense of from the front of the buffer and can be resumed once more bytes
+/// arrived.
+pub(crate) struct BodyDecoder {
+    state: BodyState,
+    max_body_size: usize,
+    body_bytes_decoded: usize,
+    trailer_bytes_parsed: usize,
+    trailers: HeadersV2,
+}
+
+impl BodyDecoder {
+    fn new(state: BodyState, max_body_size: usize) -> Self {
+        Self {
+            state,
+            max_body_size,
+            body_bytes_decoded: 0,
+            trailer_bytes_parsed: 0,
+            trailers: HeadersV2::new(),
+        }
+    }
+
+    /// Decodes body bytes from `buf` into `out`. `Complete(0)` means the body is over,
+    /// `Incomplete` that `buf` has to be refilled first.
+    pub(crate) fn decode(
+        &mut self,
+        buf: &mut BytesMut,
+        out: &mut [u8],
+    ) -> Result<ParseStatus<usize>, HttpError> {
+        loop {
+            match self.state {
+                BodyState::Body { remaining } | BodyState::ChunkData { remaining } => {
+                    if remaining > 0 && out.is_empty() {
+                        return Ok(ParseStatus::Complete(0));
+                    }
+                    let available = remaining.min(buf.len()).min(out.len());
+                    if remaining > 0 && available == 0 {
+                        return Ok(ParseStatus::Incomplete);
+                    }
+                    out[..available].copy_from_slice(&buf[..available]);
+                    buf.advance(available);
+                    self.body_bytes_decoded += available;
+                    let remaining = remaining - available;
+                    self.state = match self.state {
+                        BodyState::Body { .. } if remaining == 0 => BodyState::Done,
+                        BodyState::Body { .. } => BodyState::Body { remaining },
+                        _ if remaining == 0 => BodyState::ChunkDataEnd,
+                        _ => BodyState::ChunkData { remaining },
+                    };
+                    if available > 0 {
+                        return Ok(ParseStatus::Complete(available));
+                    }
+                }
+                BodyState::ChunkSize => {
+                    let Some(line) = BodyDecoder::next_chunk_size_line(buf)? else {
+                        return Ok(ParseStatus::Incomplete);
+                    };
+                    let chunk_size = BodyDecoder::parse_chunk_size(&line)?;
+                    if chunk_size > self.max_body_size - self.body_bytes_decoded {
+                        return Err(HttpError::PayloadTooLarge);
+                    }
+                    self.state = if chunk_size == 0 {
+                        BodyState::Trailers
+                    } else {
+                        BodyState::ChunkData {
+                            remaining: chunk_size,
+                        }
+                    };
+                }
+                BodyState::ChunkDataEnd => {
+                    if buf.len() < 2 {
+                        return Ok(ParseStatus::Incomplete);
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(HttpError::RequestParsingError(
+                            "chunk data not terminated by crlf",
+                        ));
+                    }
+                    buf.advance(2);
+                    self.state = BodyState::ChunkSize;
+                }
+                BodyState::Trailers => {
+                    let limit =
+                        MAX_HEADER_SECTION_SIZE_IN_BYTES.saturating_sub(self.trailer_bytes_parsed);
+                    let Some(line) = split_line(buf, limit, HttpError::HeaderSectionTooLarge)?
+                    else {
+                        return Ok(ParseStatus::Incomplete);
+                    };
+                    self.trailer_bytes_parsed += line.len() + 2;
+                    if line.is_empty() {
+                        self.state = BodyState::Done;
+                        continue;
+                    }
+                    let (key, val) = RequestParser::parse_header_line(line)?;
+                    self.trailers.append(key, val);
+                }
+                BodyState::Done => return Ok(ParseStatus::Complete(0)),
+            }
+        }
+    }
+
+    /// Fields sent after the last chunk, only complete once the body is over.
+    pub(crate) fn trailers(&self) -> &HeadersV2 {
+        &self.trailers
+    }
+
+    fn next_chunk_size_line(buf: &mut BytesMut) -> Result<Option<Bytes>, HttpError> {
+        let line_len = match capture_all_till_and_including_crlf(buf) {
+            Ok((rest, _)) => buf.len() - rest.len(),
+            Err(_) if buf.len() > MAX_CHUNK_SIZE_LINE_IN_BYTES => {
+                return Err(HttpError::RequestParsingError("chunk size line too long"));
+            }
+            Err(_) => return Ok(None),
+        };
+        if line_len > MAX_CHUNK_SIZE_LINE_IN_BYTES {
+            return Err(HttpError::RequestParsingError("chunk size line too long"));
+        }
+        let mut line = buf.split_to(line_len).freeze();
+        line.truncate(line_len - 2);
+        Ok(Some(line))
+    }
+
+    /// Parses `chunk-size [ chunk-ext ]`. Chunk extensions carry nothing we act upon, so they
+    /// are skipped.
+    fn parse_chunk_size(line: &[u8]) -> Result<usize, HttpError> {
+        let size = match capture_all_till_and_including_termination_character(line, b";") {
+            Ok((_extensions, size)) => size,
+            Err(_) => line,
+        };
+        let size_len = size
+            .iter()
+            .rposition(|b| *b != b' ' && *b != b'\t')
+            .map_or(0, |pos| pos + 1);
+        let size = &size[..size_len];
+        if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+            return Err(HttpError::RequestParsingError("invalid chunk size"));
+        }
+        std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size, 16).ok())
+            .ok_or(HttpError::RequestParsingError("invalid chunk size"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const MAX_BODY_SIZE: usize = 1024;
+
+    fn parse(input: &[u8]) -> Result<ParseStatus<HttpRequestV2>, HttpError> {
+        RequestParser::new(MAX_BODY_SIZE).parse(&mut BytesMut::from(input))
+    }
+
+    /// Status code of the error `input` is rejected with, `None` if it isn't rejected.
+    fn rejection(input: &[u8]) -> Option<u16> {
+        parse(input).err().and_then(|err| err.status_code())
+    }
+
+    fn header<'a>(request: &'a HttpRequestV2, name: &str) -> Option<&'a [u8]> {
+        request
+            .headers
+            .as_ref()?
+            .get(name.as_bytes())
+            .map(|value| &value[..])
+    }
+
+    #[test]
+    fn parses_head_split_at_every_offset() {
+        let input =
+            b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\nUser-Agent:  curl/8.0 \r\n\r\nrest";
+        let head_len = input.len() - b"rest".len();
+        for split in 0..input.len() {
+            let mut parser = RequestParser::new(MAX_BODY_SIZE);
+            let mut buf = BytesMut::from(&input[..split]);
+            let status = parser.parse(&mut buf).map_err(|err| err.to_string());
+            let request = match status {
+                Ok(ParseStatus::Incomplete) if split < head_len => {
+                    buf.extend_from_slice(&input[split..]);
+                    match parser.parse(&mut buf) {
+                        Ok(ParseStatus::Complete(request)) => request,
+                        _ => panic!("head split at {split} didn't complete"),
+                    }
+                }
+                Ok(ParseStatus::Complete(request)) if split >= head_len => {
+                    buf.extend_from_slice(&input[split..]);
+                    request
+                }
+                _ => panic!("unexpected status for head split at {split}"),
+            };
+            assert_eq!(request.method, Method::Get, "split at {split}");
+            assert_eq!(&request.path[..], b"/echo/abc", "split at {split}");
+            assert_eq!(request.version, HttpVersion::Http11, "split at {split}");
+            assert_eq!(header(&request, "Host"), Some(&b"localhost"[..]));
+            assert_eq!(header(&request, "user-agent"), Some(&b"curl/8.0"[..]));
+            assert_eq!(&buf[..], b"rest", "split at {split}");
+        }
+    }
+
+    #[test]
+    fn parses_requests_back_to_back() {
+        let mut parser = RequestParser::new(MAX_BODY_SIZE);
+        let mut buf = BytesMut::from(&b"GET /a HTTP/1.1\r\n\r\nHEAD /b HTTP/1.0\r\n\r\n"[..]);
+        let mut requests = Vec::new();
+        while let Ok(ParseStatus::Complete(request)) = parser.parse(&mut buf) {
+            requests.push((request.method, request.path, request.version));
+        }
+        assert_eq!(
+            requests,
+            vec![
+                (Method::Get, Bytes::from("/a"), HttpVersion::Http11),
+                (Method::Head, Bytes::from("/b"), HttpVersion::Http10),
+            ]
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn limits_request_line() {
+        let path = "a".repeat(MAX_REQUEST_LINE_SIZE_IN_BYTES);
+        let request = format!("GET /{path} HTTP/1.1\r\n\r\n");
+        assert_eq!(rejection(request.as_bytes()), Some(414));
+        // NOTE: rejected before the line is complete, a client never sending the CRLF can't
+        // make us buffer forever.
+        let unterminated = format!("GET /{path}");
+        assert_eq!(rejection(unterminated.as_bytes()), Some(414));
+    }
+
+    #[test]
+    fn limits_header_section() {
+        let field = format!("X-Filler: {}\r\n", "a".repeat(1000));
+        let fields = field.repeat(MAX_HEADER_SECTION_SIZE_IN_BYTES / field.len() + 1);
+        let request = format!("GET / HTTP/1.1\r\n{fields}\r\n");
+        assert_eq!(rejection(request.as_bytes()), Some(431));
+        let unterminated = format!("GET / HTTP/1.1\r\n{fields}");
+        assert_eq!(rejection(unterminated.as_bytes()), Some(431));
+
+        let fits = field.repeat(MAX_HEADER_SECTION_SIZE_IN_BYTES / field.len() - 1);
+        let request = format!("GET / HTTP/1.1\r\n{fits}\r\n");
+        assert!(matches!(
+            parse(request.as_bytes()),
+            Ok(ParseStatus::Complete(_))
+        ));
+    }
+
+    #[test]
+    fn tells_unknown_methods_from_garbage() {
+        assert_eq!(rejection(b"OPTIONS / HTTP/1.1\r\n\r\n"), Some(501));
+        assert_eq!(rejection(b"BREW / HTTP/1.1\r\n\r\n"), Some(501));
+        assert_eq!(rejection(b"G(ET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b" / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b"GET\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b"get / HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn tells_unsupported_versions_from_garbage() {
+        assert_eq!(rejection(b"GET / HTTP/2.0\r\n\r\n"), Some(505));
+        assert_eq!(rejection(b"GET / HTTP/0.9\r\n\r\n"), Some(505));
+        assert_eq!(rejection(b"GET / HTTP/1.1x\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b"GET / HTTX/1.1\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b"GET / http/1.1\r\n\r\n"), Some(400));
+    }
+
+    #[test]
+    fn rejects_malformed_header_lines() {
+        assert_eq!(rejection(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"), Some(400));
+        assert_eq!(rejection(b"GET / HTTP/1.1\r\n: empty\r\n\r\n"), Some(400));
+        assert_eq!(
+            rejection(b"GET / HTTP/1.1\r\nBad Key: x\r\n\r\n"),
+            Some(400)
+        );
+        assert_eq!(rejection(b"GET /\xff HTTP/1.1\r\n\r\n"), Some(400));
+    }
+
+    /// Decodes a body framed as `state` from `input`, fed to the decoder one byte at a time
+    /// and read out in small pieces, until the decoder reports the end of the body.
+    fn decode(state: BodyState, input: &[u8]) -> Result<(Vec<u8>, BodyDecoder), HttpError> {
+        let mut decoder = BodyDecoder::new(state, MAX_BODY_SIZE);
+        let mut buf = BytesMut::new();
+        let mut input = input.iter();
+        let mut body = Vec::new();
+        let mut out = [0; 3];
+        loop {
+            match decoder.decode(&mut buf, &mut out)? {
+                ParseStatus::Complete(0) => return Ok((body, decoder)),
+                ParseStatus::Complete(decoded) => body.extend_from_slice(&out[..decoded]),
+                ParseStatus::Incomplete => match input.next() {
+                    Some(byte) => buf.extend_from_slice(&[*byte]),
+                    None => return Err(HttpError::ConnectionClosed),
+                },
+            }
+        }
+    }
+
+    fn decode_chunked(input: &[u8]) -> Result<(Vec<u8>, BodyDecoder), HttpError> {
+        decode(BodyState::ChunkSize, input)
+    }
+
+    #[test]
+    fn decodes_content_length_body() -> Result<(), HttpError> {
+        let (body, _) = decode(BodyState::Body { remaining: 5 }, b"hello")?;
+        assert_eq!(body, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn decodes_chunked_body() -> Result<(), HttpError> {
+        let (body, decoder) = decode_chunked(b"5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n")?;
+        assert_eq!(body, b"hello, world");
+        assert!(decoder.trailers().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skips_chunk_extensions() -> Result<(), HttpError> {
+        let (body, _) = decode_chunked(
+            b"5;name=value;other\r\nhello\r\nA ; ext=\"x\"\r\n0123456789\r\n0;last\r\n\r\n",
+        )?;
+        assert_eq!(body, b"hello0123456789");
+        Ok(())
+    }
+
+    #[test]
+    fn collects_trailers() -> Result<(), HttpError> {
+        let (body, decoder) =
+            decode_chunked(b"2\r\nhi\r\n0\r\nChecksum: abc\r\nX-Other:  1 \r\n\r\n")?;
+        assert_eq!(body, b"hi");
+        let trailers = decoder.trailers();
+        assert_eq!(
+            trailers.get(b"Checksum").map(|value| &value[..]),
+            Some(&b"abc"[..])
+        );
+        assert_eq!(
+            trailers.get(b"X-Other").map(|value| &value[..]),
+            Some(&b"1"[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_chunk_data_without_crlf() {
+        let rejection = decode_chunked(b"5\r\nhelloX\r\n0\r\n\r\n").err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(400));
+        let rejection = decode_chunked(b"5\r\nhello\n0\r\n\r\n").err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(400));
+    }
+
+    #[test]
+    fn rejects_malformed_chunk_sizes() {
+        for input in [&b"\r\n"[..], b"x\r\n", b"-1\r\n", b"5 5\r\n", b";ext\r\n"] {
+            let rejection = decode_chunked(input).err();
+            assert_eq!(
+                rejection.and_then(|err| err.status_code()),
+                Some(400),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn limits_chunk_size_line() {
+        let mut line = b"5;".to_vec();
+        line.resize(MAX_CHUNK_SIZE_LINE_IN_BYTES, b'x');
+        let terminated = [&line[..], b"\r\n"].concat();
+        let rejection = decode_chunked(&terminated).err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(400));
+        // NOTE: rejected before the line is complete too.
+        line.push(b'x');
+        let rejection = decode_chunked(&line).err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(400));
+        let rejection = decode_chunked(b"fffffffffffffffffffff\r\n").err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(400));
+    }
+
+    #[test]
+    fn limits_chunked_body_size() {
+        let chunk = format!(
+            "{:x}\r\n{}\r\n",
+            MAX_BODY_SIZE / 2,
+            "a".repeat(MAX_BODY_SIZE / 2)
+        );
+        let input = format!("{chunk}{chunk}1\r\n");
+        let rejection = decode_chunked(input.as_bytes()).err();
+        assert_eq!(rejection.and_then(|err| err.status_code()), Some(413));
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+    }
+
+    #[test]
+    fn rejects_unsupported_transfer_codings() {
+        for coding in ["gzip", "gzip, chunked", "chunked, chunked"] {
+            let request = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {coding}\r\n\r\n");
+            assert_eq!(rejection(request.as_bytes()), Some(501), "{coding}");
+        }
+        let request = b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n";
+        assert_eq!(rejection(request), None);
+    }
+
+    #[test]
+    fn checks_repeated_content_lengths_agree() {
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(rejection(request), None);
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n";
+        assert_eq!(rejection(request), None);
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nContent-Length: -5\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: +5\r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+        let request = b"POST / HTTP/1.1\r\nContent-Length: \r\n\r\n";
+        assert_eq!(rejection(request), Some(400));
+    }
+
+    #[test]
+    fn limits_content_length() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(rejection(request.as_bytes()), Some(413));
+    }
+}
@@ -1,32 +1,75 @@
 #![allow(unused_variables)]
 #![deny(clippy::expect_used, clippy::unwrap_used)]
 
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use std::io::prelude::*;
+use anyhow::Context;
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::{net::TcpListener, sync::Arc};
 
+use files::{
+    listing::{self, ListingFormat},
+    mime::{self, MimeTypes},
+    ServedDirectory, WriteLock, WriteLocks,
+};
 use http::{
-    http_request::Method, ContentTypeHttpResponse, Headers, HttpResponseBuilder,
-    CONTENT_ENCODING_HEADER, SUPPORTED_ENCODINGS,
+    conditional::{self, Precondition, Validators},
+    encoding::{self, CompressionPolicy, EncoderRegistry, Negotiated},
+    http_request::{HttpVersion, Method},
+    range::{self, MultipartRanges, RangeRequest},
+    request_body::RequestSource,
+    Connection, ContentTypeHttpResponse, HeadersV2, HttpError, HttpResponseBuilder,
+    ACCEPT_ENCODING_HEADER, CONTENT_ENCODING_HEADER, DEFAULT_MAX_BODY_SIZE_IN_BYTES,
 };
 use itertools::Itertools;
+use middleware::{CompressionMiddleware, ContentLengthMiddleware, MiddlewareChain};
+use router::{PathParams, Router};
+use thread_pool::QueueDepth;
 
 use crate::http::{http_request::HttpRequestV2, HttpResponse};
+mod files;
 mod http;
+mod middleware;
+mod router;
 mod thread_pool;
 
-fn handle_echo_endpoint(req: &HttpRequestV2, path_str: &str) -> ContentTypeHttpResponse {
-    let body = path_str.as_bytes().to_vec();
+/// How long an idle persistent connection may hold on to a worker before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often an idle persistent connection checks whether other connections are waiting for a
+/// worker, in which case it gives its worker up.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long the client may pause while sending a request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn handle_root_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    status_response(200)
+}
+
+fn handle_echo_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    let body = params.get("text").unwrap_or_default().as_bytes().to_vec();
     let response = HttpResponseBuilder::new(200).with_body(body).build();
 
     ContentTypeHttpResponse::PlainText(response)
 }
 
-fn handle_user_agent_endpoint(req: &HttpRequestV2) -> ContentTypeHttpResponse {
+fn handle_user_agent_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
     match req.headers.as_ref() {
         Some(headers) => {
-            if let Some(val) = headers.get(&b"User-Agent"[..]) {
+            if let Some(val) = headers.get(b"User-Agent") {
                 let body = val.as_ref().to_vec();
                 ContentTypeHttpResponse::PlainText(
                     HttpResponseBuilder::new(200).with_body(body).build(),
@@ -41,146 +84,607 @@ fn handle_user_agent_endpoint(req: &HttpRequestV2) -> ContentTypeHttpResponse {
 
 fn handle_file_endpoint(
     req: &HttpRequestV2,
-    file_name: &str,
+    params: &PathParams,
     state: Arc<State>,
 ) -> ContentTypeHttpResponse {
+    let file_name = params.get("path").unwrap_or_default();
     let directory = match state.directory.as_ref() {
         Some(dir) => dir,
         None => return ContentTypeHttpResponse::NoBody(HttpResponse::default()),
     };
-    let file_path = format!("/{directory}/{file_name}");
-    match std::fs::read(file_path) {
-        Ok(content) => {
-            ContentTypeHttpResponse::File(HttpResponseBuilder::new(200).with_body(content).build())
+    let file_path = match directory.resolve(file_name) {
+        Ok(file_path) => file_path,
+        Err(err) => return status_response(err.status_code()),
+    };
+    match std::fs::metadata(&file_path) {
+        Ok(metadata) if metadata.is_dir() => handle_directory(req, &file_path, state),
+        Ok(_) => serve_file(req, &file_path, &state),
+        Err(_) => status_response(404),
+    }
+}
+
+/// Siblings of `file_path` precompressed in the codings we know the extension of, e.g.
+/// `app.js.br` for `br`, that are at least as recent as the file itself.
+fn precompressed_siblings(file_path: &Path) -> Vec<(&'static str, PathBuf)> {
+    let Ok(modified) = std::fs::metadata(file_path).and_then(|metadata| metadata.modified()) else {
+        return Vec::new();
+    };
+    ["br", "zstd", "gzip"]
+        .into_iter()
+        .filter_map(|coding| {
+            let mut sibling = file_path.as_os_str().to_owned();
+            sibling.push(".");
+            sibling.push(encoding::file_extension(coding)?);
+            let sibling = PathBuf::from(sibling);
+            // NOTE: symlinks are passed over, they could lead out of the served directory.
+            let metadata = std::fs::symlink_metadata(&sibling).ok()?;
+            let is_current = metadata
+                .modified()
+                .is_ok_and(|sibling_modified| sibling_modified >= modified);
+            (metadata.is_file() && is_current).then_some((coding, sibling))
+        })
+        .collect()
+}
+
+/// Reads the start of `file` to tell its media type, then rewinds it.
+fn sniff_content_type(
+    mime_types: &MimeTypes,
+    file_path: &Path,
+    file: &mut std::fs::File,
+) -> std::io::Result<Cow<'static, str>> {
+    let mut head = Vec::with_capacity(mime::SNIFF_LEN);
+    file.take(mime::SNIFF_LEN as u64).read_to_end(&mut head)?;
+    file.rewind()?;
+    Ok(mime_types.detect(file_path, &head))
+}
+
+fn serve_file(req: &HttpRequestV2, file_path: &Path, state: &State) -> ContentTypeHttpResponse {
+    let mut header = HeadersV2::new();
+    let mut content_coding = None;
+    let mut served_path = file_path;
+    let siblings = match state.precompressed {
+        true => precompressed_siblings(file_path),
+        false => Vec::new(),
+    };
+    if !siblings.is_empty() {
+        header.append("Vary", ACCEPT_ENCODING_HEADER);
+        let available = siblings.iter().map(|(coding, _)| *coding);
+        if let Negotiated::Coding(coding) = encoding::negotiate(req.headers.as_ref(), available) {
+            if let Some((coding, sibling)) = siblings.iter().find(|(known, _)| *known == coding) {
+                content_coding = Some(*coding);
+                served_path = sibling;
+            }
+        }
+    }
+
+    let mut file = match std::fs::File::open(served_path) {
+        Ok(file) => file,
+        Err(_) => return status_response(404),
+    };
+    let metadata = match file.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return status_response(404),
+    };
+    let validators = Validators::for_file(&metadata);
+    validators.append_to(&mut header);
+    match conditional::evaluate(req, Some(&validators)) {
+        Precondition::Passed => {}
+        Precondition::NotModified => {
+            return ContentTypeHttpResponse::NoBody(
+                HttpResponseBuilder::new(304).with_header(header).build(),
+            )
+        }
+        Precondition::Failed => return status_response(412),
+    }
+    let content_type = match state.mime_types.lookup(file_path) {
+        Some(content_type) => Ok(content_type),
+        // NOTE: the media type is the one of the file, never of its precompressed sibling.
+        None if content_coding.is_some() => {
+            std::fs::File::open(file_path).and_then(|mut original| {
+                sniff_content_type(&state.mime_types, file_path, &mut original)
+            })
         }
-        Err(_) => ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(404).build()),
+        None => sniff_content_type(&state.mime_types, file_path, &mut file),
+    };
+    let content_type = match content_type {
+        Ok(content_type) => content_type,
+        Err(err) => return status_response(files::io_error_status_code(&err)),
+    };
+    if let Some(coding) = content_coding {
+        header.append(CONTENT_ENCODING_HEADER, coding);
+    }
+
+    let len = metadata.len();
+    let range = req
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(b"Range"))
+        .filter(|_| conditional::if_range_allows(req, &validators))
+        .map_or(RangeRequest::Full, |value| range::parse_range(value, len));
+    header.append("Accept-Ranges", "bytes");
+    match range {
+        RangeRequest::Full => ContentTypeHttpResponse::File(
+            HttpResponseBuilder::new(200)
+                .with_header(header)
+                .with_file_body(file, len)
+                .build(),
+            content_type,
+        ),
+        RangeRequest::Unsatisfiable => {
+            header.append("Content-Range", format!("bytes */{len}"));
+            ContentTypeHttpResponse::NoBody(
+                HttpResponseBuilder::new(416).with_header(header).build(),
+            )
+        }
+        RangeRequest::Partial(ranges) => match ranges.as_slice() {
+            [range] => {
+                if let Err(err) = file.seek(SeekFrom::Start(range.start)) {
+                    return status_response(files::io_error_status_code(&err));
+                }
+                header.append("Content-Range", range.content_range(len));
+                ContentTypeHttpResponse::File(
+                    HttpResponseBuilder::new(206)
+                        .with_header(header)
+                        .with_file_body(file, range.len())
+                        .build(),
+                    content_type,
+                )
+            }
+            ranges => {
+                let boundary = range::multipart_boundary();
+                let body = MultipartRanges::new(file, ranges, len, &content_type, &boundary);
+                let body_len = body.len();
+                ContentTypeHttpResponse::File(
+                    HttpResponseBuilder::new(206)
+                        .with_header(header)
+                        .with_stream_body(body, Some(body_len))
+                        .build(),
+                    Cow::Owned(format!("multipart/byteranges; boundary={boundary}")),
+                )
+            }
+        },
     }
 }
 
-fn handle_file_upload_endpoint(
+/// Serves the directory's `index.html` or a listing of its contents, whichever is enabled.
+fn handle_directory(
     req: &HttpRequestV2,
-    file_name: &str,
+    dir_path: &Path,
     state: Arc<State>,
 ) -> ContentTypeHttpResponse {
+    // NOTE: the path is validated to be UTF-8 while parsing the request.
+    let target = std::str::from_utf8(&req.path).unwrap_or_default();
+    let (url_path, query) = match target.split_once('?') {
+        Some((url_path, query)) => (url_path, Some(query)),
+        None => (target, None),
+    };
+    if !url_path.ends_with('/') {
+        // NOTE: the links in the listing, and in any `index.html`, are relative to the
+        // directory, which only works when its URL ends with a slash.
+        let mut location = format!("{url_path}/");
+        if let Some(query) = query {
+            location = format!("{location}?{query}");
+        }
+        let mut header = HeadersV2::new();
+        header.append("Location", location);
+        return ContentTypeHttpResponse::NoBody(
+            HttpResponseBuilder::new(301).with_header(header).build(),
+        );
+    }
+
+    if state.serve_index {
+        let index_path = dir_path.join("index.html");
+        if index_path.is_file() {
+            return serve_file(req, &index_path, &state);
+        }
+    }
+    if !state.list_directories {
+        return status_response(404);
+    }
+
+    let format = ListingFormat::negotiate(req.headers.as_ref());
+    match listing::render(dir_path, url_path, format) {
+        Ok(body) => {
+            // NOTE: the format depends on `Accept`, which caches need to know about, RFC 9110
+            // section 12.5.5.
+            let mut header = HeadersV2::new();
+            header.append("Vary", "Accept");
+            let response = HttpResponseBuilder::new(200)
+                .with_header(header)
+                .with_body(body)
+                .build();
+            match format {
+                ListingFormat::Json => ContentTypeHttpResponse::Json(response),
+                ListingFormat::Html => ContentTypeHttpResponse::Html(response),
+            }
+        }
+        Err(err) => status_response(files::io_error_status_code(&err)),
+    }
+}
+
+/// The file a `POST`, `PUT`, `PATCH` or `DELETE` is about, locked against other writers for as
+/// long as this lives.
+struct WriteTarget<'a> {
+    path: PathBuf,
+    /// Validators of the file before the write, `None` if it doesn't exist yet.
+    current: Option<Validators>,
+    _lock: WriteLock<'a>,
+}
+
+/// Resolves and locks the file a write request is about and checks the request's
+/// preconditions against it. The error is the response to answer with right away, `409` if
+/// someone else is writing to the file.
+fn resolve_write_target<'a>(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: &'a State,
+) -> Result<WriteTarget<'a>, ContentTypeHttpResponse> {
+    let file_name = params.get("path").unwrap_or_default();
     let directory = match state.directory.as_ref() {
         Some(dir) => dir,
-        None => return ContentTypeHttpResponse::NoBody(HttpResponse::default()),
+        None => return Err(ContentTypeHttpResponse::NoBody(HttpResponse::default())),
     };
-    let body = match req.body.as_ref() {
-        Some(body) => body,
-        None => return ContentTypeHttpResponse::NoBody(HttpResponse::default()),
+    let file_path = match directory.resolve(file_name) {
+        Ok(file_path) => file_path,
+        Err(err) => return Err(status_response(err.status_code())),
     };
-    let file_path = format!("/{directory}/{file_name}");
-    match std::fs::write(file_path, body) {
-        Ok(_) => ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(201).build()),
-        Err(_) => ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(500).build()),
+    if file_path.is_dir() {
+        return Err(status_response(409));
     }
+    let Some(lock) = state.write_locks.try_lock(&file_path) else {
+        return Err(status_response(409));
+    };
+    // NOTE: checked under the lock, so nobody can change the file between the check and the
+    // write.
+    let current = std::fs::metadata(&file_path)
+        .ok()
+        .map(|metadata| Validators::for_file(&metadata));
+    if conditional::evaluate(req, current.as_ref()) != Precondition::Passed {
+        return Err(status_response(412));
+    }
+    Ok(WriteTarget {
+        path: file_path,
+        current,
+        _lock: lock,
+    })
 }
 
-fn handle_encoding(req: &HttpRequestV2, response: &mut HttpResponse) -> anyhow::Result<()> {
-    let Some(headers) = req.headers.as_ref() else {
-        return Ok(());
-    };
-    let Some(val) = headers.get(http::ACCEPT_ENCODING_HEADER.as_bytes()) else {
-        return Ok(());
+/// Response with nothing but a status code.
+fn status_response(status_code: u16) -> ContentTypeHttpResponse {
+    ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(status_code).build())
+}
+
+/// Response to a successful write, carrying the validators of the file as it is now.
+fn written_response(status_code: u16, file_path: &Path) -> ContentTypeHttpResponse {
+    let mut header = HeadersV2::new();
+    if let Ok(metadata) = std::fs::metadata(file_path) {
+        Validators::for_file(&metadata).append_to(&mut header);
+    }
+    ContentTypeHttpResponse::NoBody(
+        HttpResponseBuilder::new(status_code)
+            .with_header(header)
+            .build(),
+    )
+}
+
+/// Response to an upload that failed with `err`, either while reading the request body or
+/// while writing the file.
+fn upload_error_response(err: std::io::Error) -> ContentTypeHttpResponse {
+    let status_code = match HttpError::wrapped_in(&err) {
+        // NOTE: without a status code the client is gone or out of sync, the connection gets
+        // closed after this response anyway.
+        Some(err) => err.status_code().unwrap_or(400),
+        None => files::io_error_status_code(&err),
     };
-    if response.body.is_none() {
-        return Ok(());
+    status_response(status_code)
+}
+
+/// Streams the request body into `file_path`, replacing the file or creating it if needed. The
+/// body is never held in memory as a whole and readers never see a partially written file.
+/// Bodies over the maximum body size fail with `413`.
+fn store_file(
+    file_path: &Path,
+    req: &HttpRequestV2,
+    state: &State,
+) -> Result<(), ContentTypeHttpResponse> {
+    if state.create_dirs {
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent).map_err(upload_error_response)?;
+        }
     }
+    files::write_atomically(file_path, &req.body)
+        .map(|_| ())
+        .map_err(upload_error_response)
+}
 
-    #[allow(clippy::unwrap_used)]
-    let val = std::str::from_utf8(val).unwrap();
-    let Some(encoding) = val
-        .split(",")
-        .map(|v| v.trim())
-        .find(|v| SUPPORTED_ENCODINGS.contains(*v))
-    else {
-        return Ok(());
+fn handle_file_upload_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    if !req.body.is_present() {
+        return ContentTypeHttpResponse::NoBody(HttpResponse::default());
+    }
+    let target = match resolve_write_target(req, params, &state) {
+        Ok(target) => target,
+        Err(response) => return response,
     };
+    if let Err(response) = store_file(&target.path, req, &state) {
+        return response;
+    }
+    written_response(201, &target.path)
+}
 
-    let Some(body) = response.body.as_ref() else {
-        return Ok(());
+/// `PUT` creates the file or replaces it as a whole, `201` tells the two apart from `204`.
+fn handle_file_put_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    let target = match resolve_write_target(req, params, &state) {
+        Ok(target) => target,
+        Err(response) => return response,
     };
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(body.as_ref())?;
-    let x = encoder.finish()?;
-    response.body = Some(x);
+    if let Err(response) = store_file(&target.path, req, &state) {
+        return response;
+    }
+    let status_code = if target.current.is_some() { 204 } else { 201 };
+    written_response(status_code, &target.path)
+}
 
-    if let Some(headers) = response.header.as_mut() {
-        headers.insert(CONTENT_ENCODING_HEADER.to_string(), encoding.to_string());
-    } else {
-        let mut headers = Headers::new();
-        headers.insert(CONTENT_ENCODING_HEADER.to_string(), encoding.to_string());
-        response.header = Some(headers);
+fn handle_file_delete_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    let target = match resolve_write_target(req, params, &state) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+    if target.current.is_none() {
+        return status_response(404);
+    }
+    match std::fs::remove_file(&target.path) {
+        Ok(_) => status_response(204),
+        Err(err) => status_response(files::io_error_status_code(&err)),
     }
-    Ok(())
 }
 
-fn handle_request(req: HttpRequestV2, state: Arc<State>) -> anyhow::Result<HttpResponse> {
-    let path = &req.path;
-
-    let response = {
-        #[allow(clippy::unwrap_used)]
-        let path_splits = std::str::from_utf8(path.as_ref())
-            .unwrap()
-            .split("/")
-            .collect::<Vec<_>>();
-
-        if path_splits.len() >= 3 && path_splits[1] == "echo" {
-            // `3` is given assuming the path
-            // always starts with `/`. Most probably this assumption is wrong
-            handle_echo_endpoint(&req, path_splits[2])
-        } else if path_splits.len() >= 2 && path_splits[1] == "user-agent" {
-            handle_user_agent_endpoint(&req)
-        } else if path_splits.len() >= 3 && path_splits[1] == "files" {
-            match req.method {
-                Method::Post => handle_file_upload_endpoint(&req, path_splits[2], state.clone()),
-                _ => handle_file_endpoint(&req, path_splits[2], state.clone()),
-            }
-        } else {
-            let status_code: u16 = if path == "/" { 200 } else { 404 };
-            let response = HttpResponseBuilder::new(status_code).build();
-            ContentTypeHttpResponse::NoBody(response)
-        }
+/// `PATCH` appends the body to an existing file, or with `Content-Range: bytes first-last/*`
+/// overwrites the bytes in that range. The range may extend the file but not leave a hole.
+fn handle_file_patch_endpoint(
+    req: &HttpRequestV2,
+    params: &PathParams,
+    state: Arc<State>,
+) -> ContentTypeHttpResponse {
+    let target = match resolve_write_target(req, params, &state) {
+        Ok(target) => target,
+        Err(response) => return response,
     };
+    if target.current.is_none() {
+        return status_response(404);
+    }
+    // NOTE: the length of the body has to be checked against the range before anything is
+    // written, so it is read into memory, bounded by the maximum body size.
+    let body = match req.body.to_vec() {
+        Ok(body) => body,
+        Err(err) => return upload_error_response(err),
+    };
+    let content_range = req
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(b"Content-Range"));
 
-    let mut response = if let Some(content_type) = response.get_content_type_header_value() {
-        let mut response = response.into_inner();
-        match response.header.as_mut() {
-            Some(header) => {
-                header.insert("Content-Type".to_string(), content_type.to_string());
+    let len = match std::fs::metadata(&target.path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return status_response(files::io_error_status_code(&err)),
+    };
+    let start = match content_range {
+        None => len,
+        Some(content_range) => {
+            let Some(range) = range::parse_content_range(content_range) else {
+                return status_response(400);
+            };
+            if range.len() != body.len() as u64 {
+                return status_response(400);
             }
-            None => {
-                let mut header = Headers::new();
-                header.insert("Content-Type".to_string(), content_type.to_string());
-                response.header = Some(header);
+            if range.start > len {
+                let mut header = HeadersV2::new();
+                header.append("Content-Range", format!("bytes */{len}"));
+                return ContentTypeHttpResponse::NoBody(
+                    HttpResponseBuilder::new(416).with_header(header).build(),
+                );
             }
+            range.start
         }
-        response
-    } else {
-        response.into_inner()
     };
+    // NOTE: the patched file is put together in a copy that replaces the original, so readers
+    // never see it half written. The write lock keeps other writers from patching the same
+    // original meanwhile.
+    let patched = std::fs::File::open(&target.path).and_then(|head| {
+        let mut tail = std::fs::File::open(&target.path)?;
+        tail.seek(SeekFrom::Start(start + body.len() as u64))?;
+        let contents = head.take(start).chain(body.as_slice()).chain(tail);
+        files::write_atomically(&target.path, contents)
+    });
+    if let Err(err) = patched {
+        return status_response(files::io_error_status_code(&err));
+    }
+    written_response(204, &target.path)
+}
 
-    handle_encoding(&req, &mut response)?;
+fn handle_request(req: &mut HttpRequestV2, app: &App) -> anyhow::Result<HttpResponse> {
+    app.middlewares.run(req, |req| {
+        app.router.dispatch(req, app.state.clone()).into_response()
+    })
+}
 
-    if let Some(body) = &response.body {
-        let body_len = body.len();
-        match response.header.as_mut() {
-            Some(header) => {
-                header.insert("Content-Length".to_string(), body_len.to_string());
+/// Waits for the client to start its next request. Gives up once the connection has been idle
+/// for `IDLE_TIMEOUT`, or as soon as other connections are waiting for a worker, so idle
+/// persistent connections can't starve the pool.
+fn await_request(
+    stream: &TcpStream,
+    source: &mut RequestSource,
+    queued_connections: &QueueDepth,
+) -> io::Result<bool> {
+    if !source.buf.is_empty() {
+        return Ok(true);
+    }
+    stream.set_read_timeout(Some(IDLE_POLL_INTERVAL))?;
+    let deadline = Instant::now() + IDLE_TIMEOUT;
+    let started = loop {
+        match source.fill() {
+            Ok(0) => break false,
+            Ok(_) => break true,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if queued_connections.get() > 0 || Instant::now() >= deadline {
+                    break false;
+                }
             }
-            None => {
-                let mut header = Headers::new();
-                header.insert("Content-Length".to_string(), body_len.to_string());
-                response.header = Some(header);
+            Err(err) => return Err(err),
+        }
+    };
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    Ok(started)
+}
+
+/// Serves requests from a single client connection until either side asks for it to be closed,
+/// the client goes away or it stays idle, see `await_request`.
+fn handle_connection(stream: TcpStream, app: Arc<App>) {
+    let mut stream = stream;
+    let mut source = match stream.try_clone() {
+        Ok(read_half) => RequestSource::new(read_half),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    loop {
+        match await_request(&stream, &mut source, &app.queued_connections) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
             }
         }
+        let mut request =
+            match HttpRequestV2::create_from_tcp_stream(source, app.state.max_body_size) {
+                Ok(req) => req,
+                Err(err) => {
+                    // NOTE: after a parse error there is no telling where the next request
+                    // would start, so the connection is closed either way.
+                    let Some(mut response) = err.to_response() else {
+                        return;
+                    };
+                    response.connection = Some(Connection::Close);
+                    if let Err(e) = response.write(&mut stream) {
+                        eprintln!("{}", e);
+                    }
+                    return;
+                }
+            };
+        let mut connection = if request.keep_alive() {
+            Connection::KeepAlive
+        } else {
+            Connection::Close
+        };
+        let chunked_encoding_allowed = request.version != HttpVersion::Http10;
+        let is_head = request.method == Method::Head;
+        let mut response = match handle_request(&mut request, &app) {
+            Ok(response) => response,
+            Err(_) => {
+                let mut response = HttpResponseBuilder::new(500).build();
+                response.connection = Some(Connection::Close);
+                if let Err(e) = response.write(&mut stream) {
+                    eprintln!("{}", e);
+                }
+                return;
+            }
+        };
+        if is_head {
+            // NOTE: the response to `HEAD` is the one to `GET` without the body, headers like
+            // `Content-Length` included, RFC 9110 section 9.3.2.
+            response.body = None;
+        }
+        let body_length_unknown = response
+            .body
+            .as_ref()
+            .is_some_and(|body| body.content_length().is_none());
+        if body_length_unknown && !chunked_encoding_allowed {
+            // NOTE: without chunked encoding, closing the connection is the only way left to
+            // tell the client where the body ends.
+            connection = Connection::Close;
+        }
+        if request.body.has_failed() {
+            // NOTE: the request body couldn't be read to its end, so there is no telling where
+            // the next request would start.
+            connection = Connection::Close;
+        }
+        response.chunked_encoding_allowed = chunked_encoding_allowed;
+        response.connection = Some(connection);
+        if let Err(e) = response.write(&mut stream) {
+            eprintln!("{}", e);
+            return;
+        }
+        if connection == Connection::Close {
+            return;
+        }
+        // NOTE: whatever of the body the handler didn't read is skipped only now, so a client
+        // doesn't have to finish sending a body we already rejected before it gets to know.
+        source = match request.body.finish() {
+            Ok(source) => source,
+            Err(_) => return,
+        };
     }
-    Ok(response)
+}
+
+fn build_router() -> anyhow::Result<Router<State>> {
+    let mut router = Router::new();
+    router.route(Method::Get, "/", handle_root_endpoint)?;
+    router.route(Method::Get, "/echo/{text}", handle_echo_endpoint)?;
+    router.route(Method::Get, "/user-agent", handle_user_agent_endpoint)?;
+    router.route(Method::Get, "/files/{*path}", handle_file_endpoint)?;
+    router.route(Method::Post, "/files/{*path}", handle_file_upload_endpoint)?;
+    router.route(Method::Put, "/files/{*path}", handle_file_put_endpoint)?;
+    router.route(Method::Patch, "/files/{*path}", handle_file_patch_endpoint)?;
+    router.route(
+        Method::Delete,
+        "/files/{*path}",
+        handle_file_delete_endpoint,
+    )?;
+    Ok(router)
 }
 
 struct State {
-    directory: Option<String>,
+    directory: Option<ServedDirectory>,
+    /// Whether uploads may create the directories leading up to the uploaded file.
+    create_dirs: bool,
+    /// Whether `GET` on a directory lists its contents.
+    list_directories: bool,
+    /// Whether `GET` on a directory serves the `index.html` in it, if there is one.
+    serve_index: bool,
+    /// Media types of the served files.
+    mime_types: MimeTypes,
+    /// Whether files are served from precompressed siblings, e.g. `app.js.gz`, when the client
+    /// accepts their coding.
+    precompressed: bool,
+    max_body_size: usize,
+    write_locks: WriteLocks,
+}
+
+/// Everything a worker needs to serve a connection.
+struct App {
+    state: Arc<State>,
+    router: Router<State>,
+    middlewares: MiddlewareChain,
+    /// Connections waiting for a worker.
+    queued_connections: QueueDepth,
 }
 fn main() -> anyhow::Result<()> {
     let listener = TcpListener::bind("0.0.0.0:4221")?;
@@ -188,47 +692,147 @@ fn main() -> anyhow::Result<()> {
     let pool = thread_pool.start();
     let args = std::env::args();
     let args = args.collect::<Vec<_>>();
-    let mut state = State { directory: None };
+    let mut state = State {
+        directory: None,
+        create_dirs: args.iter().any(|a| a == "--create-dirs"),
+        list_directories: args.iter().any(|a| a == "--list-directories"),
+        serve_index: args.iter().any(|a| a == "--serve-index"),
+        precompressed: args.iter().any(|a| a == "--precompressed"),
+        max_body_size: DEFAULT_MAX_BODY_SIZE_IN_BYTES,
+        mime_types: MimeTypes::new(),
+        write_locks: WriteLocks::default(),
+    };
+    let follow_symlinks = !args.iter().any(|a| a == "--no-follow-symlinks");
     if let Some((pos, _)) = args.iter().find_position(|a| *a == "--directory") {
-        state.directory = Some(args[pos + 1].to_string());
+        state.directory = Some(ServedDirectory::new(&args[pos + 1], follow_symlinks)?);
+    }
+    let mut compression_policy = CompressionPolicy::default();
+    if let Some((pos, _)) = args.iter().find_position(|a| *a == "--compress-min-size") {
+        compression_policy = compression_policy.with_min_size(
+            args.get(pos + 1)
+                .context("--compress-min-size expects a size in bytes")?
+                .parse()
+                .context("--compress-min-size expects a size in bytes")?,
+        );
+    }
+    let compress_types = args
+        .iter()
+        .tuple_windows()
+        .filter(|(flag, _)| *flag == "--compress-type")
+        .map(|(_, media_type)| media_type.as_str())
+        .collect::<Vec<_>>();
+    if !compress_types.is_empty() {
+        compression_policy = compression_policy.with_media_types(compress_types)?;
+    }
+    if let Some((pos, _)) = args.iter().find_position(|a| *a == "--max-body-size") {
+        state.max_body_size = args
+            .get(pos + 1)
+            .context("--max-body-size expects a size in bytes")?
+            .parse()
+            .context("--max-body-size expects a size in bytes")?;
+    }
+    let mut encoders = EncoderRegistry::default();
+    for (flag, mapping) in args.iter().tuple_windows() {
+        if flag == "--mime-type" {
+            state.mime_types = state.mime_types.with_override(mapping)?;
+        } else if flag == "--compression-level" {
+            encoders = encoders.with_level(mapping)?;
+        }
     }
-    let state = Arc::new(state);
+    let app = Arc::new(App {
+        state: Arc::new(state),
+        router: build_router()?,
+        middlewares: MiddlewareChain::new()
+            .with(ContentLengthMiddleware)
+            .with(CompressionMiddleware::new(encoders, compression_policy)),
+        queued_connections: pool.queue_depth(),
+    });
 
     for stream in listener.incoming() {
         match stream {
-            Ok(mut _stream) => {
-                let state = state.clone();
-                pool.run(move || {
-                    let request = match HttpRequestV2::create_from_tcp_stream(&mut _stream) {
-                        Ok(req) => req,
-                        Err(_) => {
-                            let response = HttpResponseBuilder::new(500).build();
-                            match response.write(&mut _stream) {
-                                Ok(_) => {}
-                                Err(e) => eprintln!("{}", e),
-                            }
-                            return;
-                        }
-                    };
-                    let response = match handle_request(request, state) {
-                        Ok(response) => response,
-                        Err(_) => {
-                            let response = HttpResponseBuilder::new(500).build();
-                            match response.write(&mut _stream) {
-                                Ok(_) => {}
-                                Err(e) => eprintln!("{}", e),
-                            }
-                            return;
-                        }
-                    };
-                    match response.write(&mut _stream) {
-                        Ok(_) => {}
-                        Err(e) => eprintln!("{}", e),
-                    }
-                });
+            Ok(_stream) => {
+                let app = app.clone();
+                pool.run(move || handle_connection(_stream, app));
             }
             Err(e) => {}
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+
+    /// A connected pair of sockets: the client end, and the server end with its `RequestSource`.
+    fn connection() -> io::Result<(TcpStream, TcpStream, RequestSource)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        let source = RequestSource::new(server.try_clone()?);
+        Ok((client, server, source))
+    }
+
+    #[test]
+    fn starts_a_request_when_bytes_arrive() -> io::Result<()> {
+        let (mut client, server, mut source) = connection()?;
+        client.write_all(b"GET / HTTP/1.1\r\n")?;
+        assert!(await_request(&server, &mut source, &QueueDepth::default())?);
+        assert_eq!(&source.buf[..], b"GET / HTTP/1.1\r\n");
+        assert_eq!(server.read_timeout()?, Some(READ_TIMEOUT));
+        Ok(())
+    }
+
+    #[test]
+    fn uses_buffered_bytes_without_reading() -> io::Result<()> {
+        let (_client, server, mut source) = connection()?;
+        source.buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert!(await_request(
+            &server,
+            &mut source,
+            &QueueDepth::with_depth(1)
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_when_the_client_closes() -> io::Result<()> {
+        let (client, server, mut source) = connection()?;
+        drop(client);
+        assert!(!await_request(
+            &server,
+            &mut source,
+            &QueueDepth::default()
+        )?);
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_after_the_idle_timeout() -> io::Result<()> {
+        let (_client, server, mut source) = connection()?;
+        let started = Instant::now();
+        assert!(!await_request(
+            &server,
+            &mut source,
+            &QueueDepth::default()
+        )?);
+        assert!(started.elapsed() >= IDLE_TIMEOUT);
+        assert_eq!(server.read_timeout()?, Some(READ_TIMEOUT));
+        Ok(())
+    }
+
+    #[test]
+    fn gives_up_early_when_connections_are_queued() -> io::Result<()> {
+        let (_client, server, mut source) = connection()?;
+        let started = Instant::now();
+        assert!(!await_request(
+            &server,
+            &mut source,
+            &QueueDepth::with_depth(1)
+        )?);
+        assert!(started.elapsed() < IDLE_TIMEOUT);
+        Ok(())
+    }
+}
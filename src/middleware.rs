@@ -0,0 +1,604 @@
+use bytes::Bytes;
+use std::io::{self, Read};
+
+use crate::http::{
+    encoding::{self, CompressionPolicy, EncoderRegistry, Negotiated},
+    http_request::HttpRequestV2,
+    HeadersV2, HttpResponse, HttpResponseBuilder, ResponseBody, ACCEPT_ENCODING_HEADER,
+    CONTENT_ENCODING_HEADER,
+};
+
+/// Hook around request dispatch. `before` sees the request on its way to the handler, `after`
+/// the response on its way back to the client.
+pub(crate) trait Middleware: Send + Sync {
+    /// Returning a response short-circuits the chain: neither the handler nor the `before` of
+    /// the middlewares further down run, the returned response goes through `after` instead.
+    fn before(&self, req: &mut HttpRequestV2) -> Option<HttpResponse> {
+        None
+    }
+
+    fn after(&self, req: &HttpRequestV2, response: &mut HttpResponse) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Middlewares in the order their `before` runs. `after` runs in the opposite order, so the
+/// first middleware added is the outermost one and gets the last say on the response.
+pub(crate) struct MiddlewareChain {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    pub(crate) fn new() -> Self {
+        Self {
+            middlewares: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware + 'static,
+    {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub(crate) fn run<F>(&self, req: &mut HttpRequestV2, handler: F) -> anyhow::Result<HttpResponse>
+    where
+        F: FnOnce(&HttpRequestV2) -> HttpResponse,
+    {
+        let mut entered: usize = 0;
+        let mut short_circuited = None;
+        for middleware in self.middlewares.iter() {
+            entered += 1;
+            if let Some(response) = middleware.before(req) {
+                short_circuited = Some(response);
+                break;
+            }
+        }
+
+        let mut response = match short_circuited {
+            Some(response) => response,
+            None => handler(req),
+        };
+        for middleware in self.middlewares[..entered].iter().rev() {
+            middleware.after(req, &mut response)?;
+        }
+        Ok(response)
+    }
+}
+
+/// Compresses the response body with the coding the client prefers among the ones we support,
+/// see `encoding::negotiate`. Clients that accept neither those nor the body as is get a `406`.
+/// Bodies the policy deems not worth it, and bodies that are encoded already, go out as is.
+pub(crate) struct CompressionMiddleware {
+    encoders: EncoderRegistry,
+    policy: CompressionPolicy,
+}
+
+impl CompressionMiddleware {
+    pub(crate) fn new(encoders: EncoderRegistry, policy: CompressionPolicy) -> Self {
+        Self { encoders, policy }
+    }
+
+    /// Whether the response could have been compressed, so that it depends on
+    /// `Accept-Encoding` whether or not this one is.
+    fn is_negotiable(&self, response: &HttpResponse) -> bool {
+        let headers = response.headers();
+        let content_type = headers.and_then(|headers| headers.get(b"Content-Type"));
+        match response.status_code() {
+            // NOTE: a `304` has no `Content-Type` to tell, and the parts of a
+            // `multipart/byteranges` body could be of any type, so both are assumed to vary.
+            // Needlessly varying only costs caches some hits, not varying where the `200` does
+            // has them serve the wrong coding.
+            304 => true,
+            206 if content_type.is_some_and(|value| value.starts_with(b"multipart/byteranges")) => {
+                true
+            }
+            206 => {
+                let complete_length = headers
+                    .and_then(|headers| headers.get(b"Content-Range"))
+                    .and_then(|value| std::str::from_utf8(value).ok())
+                    .and_then(|value| value.rsplit_once('/'))
+                    .and_then(|(_, complete_length)| complete_length.parse().ok());
+                self.is_worth_compressing(content_type, complete_length)
+            }
+            _ => match response.body.as_ref() {
+                Some(body) => self.is_worth_compressing(content_type, body.content_length()),
+                None => false,
+            },
+        }
+    }
+
+    /// Whether a representation of `len` bytes, `None` when unknown, is worth compressing.
+    fn is_worth_compressing(&self, content_type: Option<&Bytes>, len: Option<u64>) -> bool {
+        content_type.is_some_and(|content_type| self.policy.allows_type(content_type))
+            && self.policy.allows_len(len)
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn after(&self, req: &HttpRequestV2, response: &mut HttpResponse) -> anyhow::Result<()> {
+        // NOTE: a precompressed file, which varies already and gains nothing from being
+        // encoded twice.
+        let is_encoded = response
+            .headers()
+            .is_some_and(|headers| headers.contains(CONTENT_ENCODING_HEADER.as_bytes()));
+        if is_encoded || !self.is_negotiable(response) {
+            return Ok(());
+        }
+        // NOTE: whatever we pick, the response now depends on `Accept-Encoding`, which caches
+        // need to know about, RFC 9110 section 12.5.5.
+        let headers = response.headers_mut();
+        let varies = headers
+            .get_list(b"Vary")
+            .any(|field| field.eq_ignore_ascii_case(ACCEPT_ENCODING_HEADER));
+        if !varies {
+            headers.append("Vary", ACCEPT_ENCODING_HEADER);
+        }
+        // NOTE: `Content-Range` counts bytes of the uncompressed file, so partial content has
+        // to go out as is.
+        if matches!(response.status_code(), 206 | 304) {
+            return Ok(());
+        }
+
+        let encoder = match encoding::negotiate(req.headers.as_ref(), self.encoders.names()) {
+            Negotiated::Coding(coding) => self.encoders.get(coding),
+            Negotiated::Identity => None,
+            Negotiated::NotAcceptable => {
+                let mut header = HeadersV2::new();
+                header.append("Vary", ACCEPT_ENCODING_HEADER);
+                *response = HttpResponseBuilder::new(406).with_header(header).build();
+                return Ok(());
+            }
+        };
+        let Some(encoder) = encoder else {
+            return Ok(());
+        };
+
+        let Some(body) = response.body.take() else {
+            return Ok(());
+        };
+        response.body = Some(match body {
+            ResponseBody::Full(body) => {
+                let mut encoded = Vec::new();
+                encoder
+                    .encode(Box::new(io::Cursor::new(body)))?
+                    .read_to_end(&mut encoded)?;
+                ResponseBody::Full(encoded)
+            }
+            // NOTE: the compressed length is only known once everything has been compressed,
+            // so streamed bodies go out chunked.
+            ResponseBody::Stream { reader, .. } => ResponseBody::Stream {
+                reader: encoder.encode(reader)?,
+                content_length: None,
+            },
+            ResponseBody::File { file, len } => ResponseBody::Stream {
+                reader: encoder.encode(Box::new(file.take(len)))?,
+                content_length: None,
+            },
+        });
+
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_ENCODING_HEADER, encoder.name());
+        // NOTE: the compressed body is not byte for byte the representation the strong tag was
+        // made for, so it is only weakly equivalent, RFC 9110 section 8.8.3.
+        if let Some(etag) = headers.get(b"ETag").filter(|etag| etag.starts_with(b"\"")) {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag);
+            headers.insert("ETag", weak);
+        }
+        Ok(())
+    }
+}
+
+/// Sets `Content-Length` for bodies whose length is known upfront. Responses without a body get
+/// an explicit `Content-Length: 0`, otherwise a client on a persistent connection has no way to
+/// tell that the response is over.
+pub(crate) struct ContentLengthMiddleware;
+
+impl Middleware for ContentLengthMiddleware {
+    fn after(&self, req: &HttpRequestV2, response: &mut HttpResponse) -> anyhow::Result<()> {
+        let body_len = match response.body.as_ref() {
+            Some(body) => body.content_length(),
+            // NOTE: these never carry a body, RFC 9110 section 8.6.
+            None if matches!(response.status_code(), 100..=199 | 204 | 304) => None,
+            None => Some(0),
+        };
+        if let Some(body_len) = body_len {
+            response
+                .headers_mut()
+                .insert("Content-Length", body_len.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{
+        request_parser::{ParseStatus, RequestParser},
+        HttpError,
+    };
+    use bytes::BytesMut;
+    use flate2::read::GzDecoder;
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+
+    fn request(headers: &[(&str, &str)]) -> Result<HttpRequestV2, HttpError> {
+        let mut head = "GET /file HTTP/1.1\r\n".to_string();
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        match RequestParser::new(0).parse(&mut BytesMut::from(head.as_str()))? {
+            ParseStatus::Complete(request) => Ok(request),
+            ParseStatus::Incomplete => Err(HttpError::ConnectionClosed),
+        }
+    }
+
+    fn response(status_code: u16, headers: &[(&str, &str)], body: &[u8]) -> HttpResponse {
+        let mut header = HeadersV2::new();
+        for (name, value) in headers {
+            header.append(name.to_string(), value.to_string());
+        }
+        HttpResponseBuilder::new(status_code)
+            .with_header(header)
+            .with_body(body.to_vec())
+            .build()
+    }
+
+    fn header(response: &HttpResponse, name: &str) -> Option<String> {
+        let value = response.headers()?.get(name.as_bytes())?;
+        Some(String::from_utf8_lossy(value).into_owned())
+    }
+
+    fn body(response: &HttpResponse) -> Option<&[u8]> {
+        match response.body.as_ref()? {
+            ResponseBody::Full(body) => Some(body),
+            _ => None,
+        }
+    }
+
+    /// Runs `response` through a `CompressionMiddleware` with the default encoders and policy.
+    fn compressed(
+        accept_encoding: &str,
+        mut response: HttpResponse,
+    ) -> anyhow::Result<HttpResponse> {
+        let req = request(&[(ACCEPT_ENCODING_HEADER, accept_encoding)])?;
+        CompressionMiddleware::new(EncoderRegistry::default(), CompressionPolicy::default())
+            .after(&req, &mut response)?;
+        Ok(response)
+    }
+
+    fn text() -> Vec<u8> {
+        "compress me, ".repeat(100).into_bytes()
+    }
+
+    #[test]
+    fn compresses_with_the_negotiated_coding() -> anyhow::Result<()> {
+        let text = text();
+        let response = compressed(
+            "gzip",
+            response(200, &[("Content-Type", "text/plain; charset=utf-8")], &text),
+        )?;
+        assert_eq!(
+            header(&response, "Content-Encoding").as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(
+            header(&response, "Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+        let mut decoded = Vec::new();
+        GzDecoder::new(body(&response).unwrap_or_default()).read_to_end(&mut decoded)?;
+        assert_eq!(decoded, text);
+        Ok(())
+    }
+
+    #[test]
+    fn identity_still_varies() -> anyhow::Result<()> {
+        let text = text();
+        let response = compressed(
+            "identity",
+            response(
+                200,
+                &[("Content-Type", "text/plain"), ("ETag", "\"1\"")],
+                &text,
+            ),
+        )?;
+        assert_eq!(header(&response, "Content-Encoding"), None);
+        assert_eq!(
+            header(&response, "Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+        assert_eq!(header(&response, "ETag").as_deref(), Some("\"1\""));
+        assert_eq!(body(&response), Some(&text[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_small_and_incompressible_bodies_alone() -> anyhow::Result<()> {
+        let text = text();
+        for response in [
+            response(200, &[("Content-Type", "text/plain")], b"short"),
+            response(200, &[("Content-Type", "image/png")], &text),
+            response(200, &[], &text),
+        ] {
+            let response = compressed("gzip", response)?;
+            assert_eq!(header(&response, "Content-Encoding"), None);
+            assert_eq!(header(&response, "Vary"), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_encoded_bodies_alone() -> anyhow::Result<()> {
+        let text = text();
+        let response = compressed(
+            "gzip",
+            response(
+                200,
+                &[("Content-Type", "text/plain"), ("Content-Encoding", "br")],
+                &text,
+            ),
+        )?;
+        assert_eq!(header(&response, "Content-Encoding").as_deref(), Some("br"));
+        assert_eq!(header(&response, "Vary"), None);
+        assert_eq!(body(&response), Some(&text[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn answers_not_acceptable_when_nothing_is() -> anyhow::Result<()> {
+        let response = compressed(
+            "identity;q=0, *;q=0",
+            response(200, &[("Content-Type", "text/plain")], &text()),
+        )?;
+        assert_eq!(response.status_code(), 406);
+        assert_eq!(
+            header(&response, "Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+        assert_eq!(header(&response, "Content-Type"), None);
+        assert_eq!(body(&response), None);
+        Ok(())
+    }
+
+    #[test]
+    fn weakens_strong_etags_of_encoded_bodies() -> anyhow::Result<()> {
+        let strong = compressed(
+            "gzip",
+            response(
+                200,
+                &[("Content-Type", "text/plain"), ("ETag", "\"1\"")],
+                &text(),
+            ),
+        )?;
+        assert_eq!(header(&strong, "ETag").as_deref(), Some("W/\"1\""));
+
+        let weak = compressed(
+            "gzip",
+            response(
+                200,
+                &[("Content-Type", "text/plain"), ("ETag", "W/\"1\"")],
+                &text(),
+            ),
+        )?;
+        assert_eq!(header(&weak, "ETag").as_deref(), Some("W/\"1\""));
+        Ok(())
+    }
+
+    #[test]
+    fn adds_vary_only_once() -> anyhow::Result<()> {
+        let response = compressed(
+            "gzip",
+            response(
+                200,
+                &[
+                    ("Content-Type", "text/plain"),
+                    ("Vary", "Accept, accept-encoding"),
+                ],
+                &text(),
+            ),
+        )?;
+        let vary = response
+            .headers()
+            .map(|headers| headers.get_all(b"Vary").count());
+        assert_eq!(vary, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn partial_and_not_modified_responses_vary_but_stay_unencoded() -> anyhow::Result<()> {
+        let text = text();
+        let not_modified = compressed(
+            "gzip",
+            HttpResponseBuilder::new(304)
+                .with_header(HeadersV2::new())
+                .build(),
+        )?;
+        assert_eq!(
+            header(&not_modified, "Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+
+        let partial = compressed(
+            "gzip",
+            response(
+                206,
+                &[
+                    ("Content-Type", "text/plain"),
+                    ("Content-Range", "bytes 0-9/1300"),
+                ],
+                &text[..10],
+            ),
+        )?;
+        assert_eq!(header(&partial, "Vary").as_deref(), Some("Accept-Encoding"));
+        assert_eq!(header(&partial, "Content-Encoding"), None);
+        assert_eq!(body(&partial), Some(&text[..10]));
+
+        let small_file = compressed(
+            "gzip",
+            response(
+                206,
+                &[
+                    ("Content-Type", "text/plain"),
+                    ("Content-Range", "bytes 0-9/20"),
+                ],
+                &text[..10],
+            ),
+        )?;
+        assert_eq!(header(&small_file, "Vary"), None);
+        Ok(())
+    }
+
+    type Fields = Vec<(String, String)>;
+
+    /// Records the calls to it in a log shared with the other middlewares of a chain.
+    struct Recording {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        short_circuits: bool,
+    }
+
+    impl Recording {
+        fn record(&self, event: String) {
+            if let Ok(mut log) = self.log.lock() {
+                log.push(event);
+            }
+        }
+    }
+
+    impl Middleware for Recording {
+        fn before(&self, req: &mut HttpRequestV2) -> Option<HttpResponse> {
+            self.record(format!("before {}", self.name));
+            self.short_circuits.then(|| {
+                let mut header = HeadersV2::new();
+                header.append("Answered-By", self.name);
+                HttpResponseBuilder::new(403).with_header(header).build()
+            })
+        }
+
+        fn after(&self, req: &HttpRequestV2, response: &mut HttpResponse) -> anyhow::Result<()> {
+            self.record(format!("after {}", self.name));
+            response.headers_mut().append("Seen-By", self.name);
+            Ok(())
+        }
+    }
+
+    /// Runs a chain of `Recording` middlewares named `names`, the one named `short_circuit`
+    /// answering in `before`. Returns the log, and the response's headers.
+    fn run_chain(
+        names: &[&'static str],
+        short_circuit: Option<&str>,
+    ) -> anyhow::Result<(Vec<String>, Fields)> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let chain = names.iter().fold(MiddlewareChain::new(), |chain, name| {
+            chain.with(Recording {
+                name,
+                log: log.clone(),
+                short_circuits: short_circuit == Some(*name),
+            })
+        });
+        let mut req = request(&[])?;
+        let response = chain.run(&mut req, |_| {
+            if let Ok(mut log) = log.lock() {
+                log.push("handler".to_string());
+            }
+            HttpResponseBuilder::new(200).build()
+        })?;
+        let headers = response
+            .headers()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|(name, value)| {
+                        (
+                            String::from_utf8_lossy(name).into_owned(),
+                            String::from_utf8_lossy(value).into_owned(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let log = log.lock().map(|log| log.clone()).unwrap_or_default();
+        Ok((log, headers))
+    }
+
+    fn owned(pairs: &[(&str, &str)]) -> Fields {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn after_runs_in_reverse_order() -> anyhow::Result<()> {
+        let (log, headers) = run_chain(&["a", "b", "c"], None)?;
+        assert_eq!(
+            log,
+            vec!["before a", "before b", "before c", "handler", "after c", "after b", "after a"]
+        );
+        assert_eq!(
+            headers,
+            owned(&[("Seen-By", "c"), ("Seen-By", "b"), ("Seen-By", "a")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn before_can_short_circuit() -> anyhow::Result<()> {
+        let (log, headers) = run_chain(&["a", "b", "c"], Some("b"))?;
+        assert_eq!(log, vec!["before a", "before b", "after b", "after a"]);
+        assert_eq!(
+            headers,
+            owned(&[("Answered-By", "b"), ("Seen-By", "b"), ("Seen-By", "a")])
+        );
+        Ok(())
+    }
+
+    fn with_content_length(mut response: HttpResponse) -> anyhow::Result<Option<String>> {
+        ContentLengthMiddleware.after(&request(&[])?, &mut response)?;
+        Ok(header(&response, "Content-Length"))
+    }
+
+    #[test]
+    fn sets_content_length_of_known_bodies() -> anyhow::Result<()> {
+        assert_eq!(
+            with_content_length(response(200, &[], b"hello"))?.as_deref(),
+            Some("5")
+        );
+        assert_eq!(
+            with_content_length(response(200, &[("Content-Length", "99")], b"hello"))?.as_deref(),
+            Some("5")
+        );
+        let streamed = HttpResponseBuilder::new(200)
+            .with_stream_body(io::empty(), Some(7))
+            .build();
+        assert_eq!(with_content_length(streamed)?.as_deref(), Some("7"));
+        let chunked = HttpResponseBuilder::new(200)
+            .with_stream_body(io::empty(), None)
+            .build();
+        assert_eq!(with_content_length(chunked)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn empty_responses_get_an_explicit_zero() -> anyhow::Result<()> {
+        for status_code in [200, 201, 404, 405, 412, 500] {
+            let response = HttpResponseBuilder::new(status_code).build();
+            assert_eq!(
+                with_content_length(response)?.as_deref(),
+                Some("0"),
+                "{status_code}"
+            );
+        }
+        for status_code in [100, 101, 204, 304] {
+            let response = HttpResponseBuilder::new(status_code).build();
+            assert_eq!(with_content_length(response)?, None, "{status_code}");
+        }
+        Ok(())
+    }
+}
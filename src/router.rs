@@ -0,0 +1,343 @@
+use std::sync::Arc;
+
+use crate::http::{
+    http_request::{HttpRequestV2, Method},
+    ContentTypeHttpResponse, HeadersV2, HttpResponseBuilder,
+};
+
+pub(crate) type Handler<S> = Box<
+    dyn Fn(&HttpRequestV2, &PathParams, Arc<S>) -> ContentTypeHttpResponse + Send + Sync + 'static,
+>;
+
+/// Values captured by the `{name}` and `{*name}` segments of a route's pattern.
+#[derive(Debug, Default)]
+pub(crate) struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `{name}`, matches exactly one segment.
+    Param(String),
+    /// `{*name}`, matches the rest of the path, slashes included. Only allowed last.
+    CatchAll(String),
+}
+
+struct Route<S> {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Handler<S>,
+}
+
+/// Dispatches requests to the handler registered for their method and path. Patterns are made
+/// of `/` separated segments which are either literals, `{name}` to capture one segment, or
+/// `{*name}` to capture everything that follows.
+pub(crate) struct Router<S> {
+    routes: Vec<Route<S>>,
+}
+
+impl<S> Router<S> {
+    pub(crate) fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub(crate) fn route<F>(
+        &mut self,
+        method: Method,
+        pattern: &str,
+        handler: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(&HttpRequestV2, &PathParams, Arc<S>) -> ContentTypeHttpResponse
+            + Send
+            + Sync
+            + 'static,
+    {
+        let segments = Router::<S>::parse_pattern(pattern)?;
+        self.routes.push(Route {
+            method,
+            segments,
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    fn parse_pattern(pattern: &str) -> anyhow::Result<Vec<Segment>> {
+        let Some(pattern) = pattern.strip_prefix('/') else {
+            anyhow::bail!("route pattern `{pattern}` must start with `/`");
+        };
+        let mut segments = Vec::new();
+        let mut splits = pattern.split('/').peekable();
+        while let Some(split) = splits.next() {
+            let segment = match split.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => match name.strip_prefix('*') {
+                    Some(name) if splits.peek().is_some() => {
+                        anyhow::bail!("catch-all `{{*{name}}}` must be the last segment")
+                    }
+                    Some(name) => Segment::CatchAll(name.to_string()),
+                    None => Segment::Param(name.to_string()),
+                },
+                None => Segment::Literal(split.to_string()),
+            };
+            segments.push(segment);
+        }
+        Ok(segments)
+    }
+
+    /// Matches `path` against `segments`, returning the captured parameters.
+    fn match_path(segments: &[Segment], path: &str) -> Option<PathParams> {
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let mut params = PathParams::default();
+        let mut splits = path.split('/');
+        for segment in segments {
+            match segment {
+                Segment::CatchAll(name) => {
+                    let rest = splits.collect::<Vec<_>>().join("/");
+                    params.params.push((name.clone(), rest));
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if splits.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let split = splits.next()?;
+                    if split.is_empty() {
+                        return None;
+                    }
+                    params.params.push((name.clone(), split.to_string()));
+                }
+            }
+        }
+        if splits.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+
+    /// Runs the handler matching the request, `HEAD` falls back to the `GET` route. Paths no
+    /// route matches get a `404`, paths that only match for other methods a `405` listing those
+    /// methods in `Allow`.
+    pub(crate) fn dispatch(&self, req: &HttpRequestV2, state: Arc<S>) -> ContentTypeHttpResponse {
+        // NOTE: the path is validated to be UTF-8 while parsing the request.
+        let path = std::str::from_utf8(&req.path).unwrap_or_default();
+        let path = path.split_once('?').map_or(path, |(path, _query)| path);
+
+        let mut allowed_methods: Vec<Method> = Vec::new();
+        let mut get_fallback = None;
+        for route in self.routes.iter() {
+            let Some(params) = Router::<S>::match_path(&route.segments, path) else {
+                continue;
+            };
+            if route.method == req.method {
+                return (route.handler)(req, &params, state);
+            }
+            if route.method == Method::Get && get_fallback.is_none() {
+                get_fallback = Some((route, params));
+            }
+            if !allowed_methods.contains(&route.method) {
+                allowed_methods.push(route.method);
+            }
+        }
+        // NOTE: every `GET` route answers `HEAD` too, the body is dropped when the response is
+        // written.
+        if let Some((route, params)) = get_fallback {
+            if req.method == Method::Head {
+                return (route.handler)(req, &params, state);
+            }
+            if !allowed_methods.contains(&Method::Head) {
+                allowed_methods.push(Method::Head);
+            }
+        }
+
+        if allowed_methods.is_empty() {
+            return ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(404).build());
+        }
+        let allow = allowed_methods
+            .iter()
+            .map(|method| method.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut header = HeadersV2::new();
+        header.append("Allow", allow);
+        ContentTypeHttpResponse::NoBody(HttpResponseBuilder::new(405).with_header(header).build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{
+        request_parser::{ParseStatus, RequestParser},
+        HttpError,
+    };
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+
+    /// A handler answering with which route matched and what it captured.
+    fn echo(
+        route: &'static str,
+    ) -> impl Fn(&HttpRequestV2, &PathParams, Arc<()>) -> ContentTypeHttpResponse {
+        move |_, params, _| {
+            let mut header = HeadersV2::new();
+            header.append("Route", route);
+            for (name, value) in params.params.iter() {
+                header.append(name.clone(), value.clone());
+            }
+            ContentTypeHttpResponse::NoBody(
+                HttpResponseBuilder::new(200).with_header(header).build(),
+            )
+        }
+    }
+
+    fn router() -> anyhow::Result<Router<()>> {
+        let mut router = Router::new();
+        router.route(Method::Get, "/", echo("root"))?;
+        router.route(Method::Get, "/echo/{text}", echo("echo"))?;
+        router.route(Method::Get, "/files/{*path}", echo("get file"))?;
+        router.route(Method::Put, "/files/{*path}", echo("put file"))?;
+        router.route(Method::Delete, "/files/{*path}", echo("delete file"))?;
+        router.route(Method::Post, "/upload", echo("upload"))?;
+        Ok(router)
+    }
+
+    fn request(method: &str, path: &str) -> Result<HttpRequestV2, HttpError> {
+        let head = format!("{method} {path} HTTP/1.1\r\n\r\n");
+        match RequestParser::new(0).parse(&mut BytesMut::from(head.as_str()))? {
+            ParseStatus::Complete(request) => Ok(request),
+            ParseStatus::Incomplete => Err(HttpError::ConnectionClosed),
+        }
+    }
+
+    /// The status code, and the headers as strings.
+    fn dispatch(method: &str, path: &str) -> anyhow::Result<(u16, Vec<(String, String)>)> {
+        let response = router()?
+            .dispatch(&request(method, path)?, Arc::new(()))
+            .into_response();
+        let headers = response
+            .headers()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|(name, value)| {
+                        (
+                            String::from_utf8_lossy(name).into_owned(),
+                            String::from_utf8_lossy(value).into_owned(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok((response.status_code(), headers))
+    }
+
+    fn matched(route: &str, params: &[(&str, &str)]) -> (u16, Vec<(String, String)>) {
+        let mut headers = vec![("Route".to_string(), route.to_string())];
+        headers.extend(
+            params
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        );
+        (200, headers)
+    }
+
+    fn allowed(methods: &str) -> (u16, Vec<(String, String)>) {
+        (405, vec![("Allow".to_string(), methods.to_string())])
+    }
+
+    #[test]
+    fn captures_segments() -> anyhow::Result<()> {
+        assert_eq!(dispatch("GET", "/")?, matched("root", &[]));
+        assert_eq!(
+            dispatch("GET", "/echo/abc")?,
+            matched("echo", &[("text", "abc")])
+        );
+        assert_eq!(
+            dispatch("GET", "/files/a/b/c.txt")?,
+            matched("get file", &[("path", "a/b/c.txt")])
+        );
+        assert_eq!(dispatch("GET", "/echo/abc/def")?.0, 404);
+        assert_eq!(dispatch("GET", "/nope")?.0, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn catch_all_matches_an_empty_rest() -> anyhow::Result<()> {
+        assert_eq!(
+            dispatch("GET", "/files")?,
+            matched("get file", &[("path", "")])
+        );
+        assert_eq!(
+            dispatch("GET", "/files/")?,
+            matched("get file", &[("path", "")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn params_do_not_match_empty_segments() -> anyhow::Result<()> {
+        assert_eq!(dispatch("GET", "/echo/")?.0, 404);
+        assert_eq!(dispatch("GET", "/echo")?.0, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn strips_the_query_string() -> anyhow::Result<()> {
+        assert_eq!(
+            dispatch("GET", "/echo/abc?x=1/2")?,
+            matched("echo", &[("text", "abc")])
+        );
+        assert_eq!(dispatch("GET", "/?x")?, matched("root", &[]));
+        Ok(())
+    }
+
+    #[test]
+    fn head_falls_back_to_get() -> anyhow::Result<()> {
+        assert_eq!(
+            dispatch("HEAD", "/echo/abc")?,
+            matched("echo", &[("text", "abc")])
+        );
+        assert_eq!(dispatch("HEAD", "/upload")?, allowed("POST"));
+        Ok(())
+    }
+
+    #[test]
+    fn lists_allowed_methods() -> anyhow::Result<()> {
+        assert_eq!(
+            dispatch("POST", "/files/a.txt")?,
+            allowed("GET, PUT, DELETE, HEAD")
+        );
+        assert_eq!(dispatch("GET", "/upload")?, allowed("POST"));
+        assert_eq!(dispatch("DELETE", "/echo/abc")?, allowed("GET, HEAD"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_patterns() -> anyhow::Result<()> {
+        assert_eq!(
+            Router::<()>::parse_pattern("/files/{name}/{*rest}")?,
+            vec![
+                Segment::Literal("files".to_string()),
+                Segment::Param("name".to_string()),
+                Segment::CatchAll("rest".to_string()),
+            ]
+        );
+        assert!(Router::<()>::parse_pattern("/files/{*rest}/more").is_err());
+        assert!(Router::<()>::parse_pattern("files/{name}").is_err());
+        assert!(Router::<()>::new()
+            .route(Method::Get, "echo/{text}", echo("echo"))
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -22,6 +23,22 @@ where
     // issue?
     end_chan: (Sender<()>, Arc<Mutex<Receiver<()>>>),
     worker_chan: (Sender<T>, Arc<Mutex<Receiver<T>>>),
+    queued: QueueDepth,
+}
+
+/// How many jobs are waiting for a worker.
+#[derive(Clone, Default)]
+pub(crate) struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_depth(depth: usize) -> Self {
+        Self(Arc::new(AtomicUsize::new(depth)))
+    }
 }
 
 #[derive(Clone)]
@@ -44,6 +61,7 @@ where
             capacity,
             end_chan: (tx, Arc::new(Mutex::new(rx))),
             worker_chan: (worker_tx, Arc::new(Mutex::new(worker_rx))),
+            queued: QueueDepth::default(),
         });
         ThreadPool { _inner }
     }
@@ -70,6 +88,7 @@ where
             for _ in 0..pool.capacity {
                 let (_, worker_rx) = &pool.worker_chan;
                 let worker_rx = worker_rx.clone();
+                let queued = pool.queued.clone();
                 thread::spawn(move || loop {
                     let item = match worker_rx.lock() {
                         Ok(guard) => match guard.recv() {
@@ -80,6 +99,7 @@ where
                             continue;
                         }
                     };
+                    queued.0.fetch_sub(1, Ordering::Relaxed);
                     item();
                 });
             }
@@ -98,6 +118,7 @@ where
             capacity: pool.capacity,
             end_chan: pool.end_chan.clone(),
             worker_chan: pool.worker_chan.clone(),
+            queued: pool.queued.clone(),
         };
         ThreadPool {
             _inner: Arc::new(_inner),
@@ -111,7 +132,14 @@ where
 {
     pub(crate) fn run(&self, f: T) {
         let (tx, _) = &self._inner.worker_chan;
-        let _ = tx.send(f);
+        self._inner.queued.0.fetch_add(1, Ordering::Relaxed);
+        if tx.send(f).is_err() {
+            self._inner.queued.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn queue_depth(&self) -> QueueDepth {
+        self._inner.queued.clone()
     }
 }
 
@@ -123,3 +151,56 @@ where
         let _ = self._inner.end_chan.0.send(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, Instant};
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    fn wait_for(depth: &QueueDepth, expected: usize) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while depth.get() != expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        depth.get()
+    }
+
+    #[test]
+    fn counts_jobs_waiting_for_a_worker() {
+        let pool = ThreadPoolBuilder {}.build::<Job>().start();
+        let depth = pool.queue_depth();
+        assert_eq!(depth.get(), 0);
+
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        for _ in 0..pool._inner.capacity + 2 {
+            let released = released.clone();
+            pool.run(Box::new(move || {
+                if let Ok(released) = released.lock() {
+                    let _ = released.recv();
+                }
+            }));
+        }
+        // NOTE: every worker is blocked, so the last two jobs stay queued.
+        assert_eq!(wait_for(&depth, 2), 2);
+
+        drop(release);
+        assert_eq!(wait_for(&depth, 0), 0);
+    }
+
+    #[test]
+    fn queue_depth_handles_share_the_count() {
+        let pool = ThreadPoolBuilder {}.build::<Job>().start();
+        let depth = pool.queue_depth();
+        let (done, finished) = mpsc::channel();
+        pool.run(Box::new(move || {
+            let _ = done.send(());
+        }));
+        let _ = finished.recv_timeout(Duration::from_secs(5));
+        assert_eq!(wait_for(&pool.queue_depth(), 0), 0);
+        assert_eq!(depth.get(), 0);
+    }
+}